@@ -1,16 +1,25 @@
-use anyhow::Result;
-use bb::{BbAesIv, BbAesKey};
-use clap::Parser;
+use bb::{bootrom_keys, BbAesIv, BbAesKey, Virage2};
+use clap::{
+    value_parser, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+};
 use clap_num::maybe_hex;
+use flate2::Compression;
 use hex::FromHex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Deserialize;
 
 use std::ffi::OsString;
 use std::fmt::{self, Display, Formatter};
 use std::fs::{read, read_to_string, write};
 use std::io::{stdout, Error, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Debug)]
+use crate::keyfile::{self, Profile};
+use crate::MakeSKSAError;
+
+#[derive(Debug, Clone)]
 pub enum IOType {
     Stdin,
     Stdout,
@@ -18,38 +27,38 @@ pub enum IOType {
 }
 
 impl IOType {
-    pub fn read(&self) -> Result<Vec<u8>, Error> {
+    pub fn read(&self) -> Result<Vec<u8>, MakeSKSAError> {
         match self {
             Self::Stdin => {
                 let mut rv = vec![];
-                std::io::stdin().lock().read_to_end(&mut rv)?;
-                Ok(rv)
+                std::io::stdin().lock().read_to_end(&mut rv).map(|_| rv)
             }
             Self::Stdout => Err(Error::from(ErrorKind::Unsupported)),
             Self::File(path) => read(path),
         }
-        .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, self)))
+        .map_err(|e| MakeSKSAError::Io(e, self.to_string()))
     }
 
-    pub fn read_string(&self) -> Result<String, Error> {
+    pub fn read_string(&self) -> Result<String, MakeSKSAError> {
         match self {
             Self::Stdin => {
                 let mut rv = String::new();
-                std::io::stdin().lock().read_to_string(&mut rv)?;
-                Ok(rv)
+                std::io::stdin().lock().read_to_string(&mut rv).map(|_| rv)
             }
             Self::Stdout => Err(Error::from(ErrorKind::Unsupported)),
             Self::File(path) => read_to_string(path),
         }
-        .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, self)))
+        .map_err(|e| MakeSKSAError::Io(e, self.to_string()))
     }
 
-    pub fn write<T: AsRef<[u8]>>(&self, data: T) -> Result<usize, Error> {
+    pub fn write<T: AsRef<[u8]>>(&self, data: T) -> Result<usize, MakeSKSAError> {
         match self {
             Self::Stdin => Err(Error::from(ErrorKind::Unsupported)),
-            Self::Stdout => stdout().write(data.as_ref()),
-            Self::File(path) => write(path, &data).and(Ok(data.as_ref().len())),
+            Self::Stdout => stdout().write_all(data.as_ref()),
+            Self::File(path) => write(path, &data),
         }
+        .and(Ok(data.as_ref().len()))
+        .map_err(|e| MakeSKSAError::Io(e, self.to_string()))
     }
 
     fn input<T: AsRef<str>>(path: T) -> Self {
@@ -66,15 +75,7 @@ impl IOType {
         }
     }
 
-    fn derive_input<F: FnOnce(&PathBuf) -> PathBuf>(&self, f: F) -> Self {
-        match self {
-            Self::Stdin => Self::Stdin,
-            Self::Stdout => Self::Stdin,
-            Self::File(p) => Self::File(f(p)),
-        }
-    }
-
-    fn derive_output<F: FnOnce(&PathBuf) -> PathBuf>(&self, f: F) -> Self {
+    pub(crate) fn derive_output<F: FnOnce(&PathBuf) -> PathBuf>(&self, f: F) -> Self {
         match self {
             Self::Stdin => Self::Stdout,
             Self::Stdout => Self::Stdout,
@@ -98,23 +99,57 @@ impl Display for IOType {
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
-    /// Input Virage2 (used for key derivation)
-    virage2: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input Virage2 (used for key derivation; left out when the boot app key is given instead)
+    #[arg(required = true, conflicts_with("boot_app_key"))]
+    virage2: Option<String>,
 
-    /// Input bootrom (used for key derivation)
-    bootrom: String,
+    /// Input bootrom (used for key derivation; left out when the SK key and IV are given instead)
+    #[arg(required = true, conflicts_with("sk_key"))]
+    bootrom: Option<String>,
 
     /// Input SK
-    sk: String,
+    #[arg(required = true)]
+    sk: Option<String>,
 
     /// Input SA1
-    sa1: String,
+    #[arg(required = true)]
+    sa1: Option<String>,
 
     /// Input SA1 CID
-    #[arg(value_parser=maybe_hex::<u32>)]
-    sa1_cid: u32,
+    #[arg(required = true, value_parser=maybe_hex::<u32>)]
+    sa1_cid: Option<u32>,
+
+    /// Boot app key, as hex or a file holding it, instead of the Virage2
+    #[arg(long)]
+    boot_app_key: Option<String>,
+
+    /// SK encryption key, as hex or a file holding it, instead of the bootrom
+    #[arg(long, requires("sk_iv"))]
+    sk_key: Option<String>,
+
+    /// SK encryption IV, as hex or a file holding it, instead of the bootrom
+    #[arg(long, requires("sk_key"))]
+    sk_iv: Option<String>,
+
+    /// Keyfile (TOML, or JSON with a .json extension) holding named per-console key sets (optional)
+    #[arg(long, requires("profile"))]
+    keyfile: Option<String>,
+
+    /// Profile in the keyfile to take any keys not given on the command line from
+    #[arg(long, requires("keyfile"))]
+    profile: Option<String>,
 
     /// Input SA1 encryption key (optional)
     #[arg(long)]
@@ -128,6 +163,14 @@ struct Cli {
     #[arg(long)]
     sa1_key_iv: Option<String>,
 
+    /// SA1 compression: none, deflate, best, precompressed, or a deflate level from 0 to 9 (defaults to none)
+    #[arg(long)]
+    sa1_compression: Option<SACompression>,
+
+    /// Virtual address SA1 has to load at, checked against its boot header or ELF segments (optional)
+    #[arg(long, value_parser=maybe_hex::<u32>)]
+    sa1_load_address: Option<u32>,
+
     /// Input SA2 (optional)
     #[arg(requires("sa2_cid"))]
     sa2: Option<String>,
@@ -145,120 +188,1128 @@ struct Cli {
     sa2_iv: Option<String>,
 
     /// Input SA2 key IV (optional)
-    #[arg(long)]
+    #[arg(long, requires("sa2"))]
     sa2_key_iv: Option<String>,
 
+    /// Draw any SA keys and IVs not given from the OS RNG instead of leaving them all zero
+    #[arg(long)]
+    random_keys: bool,
+
+    /// Blocks reserved for the SKSA; the build fails if it needs more (also prints a per-region report)
+    #[arg(long, value_parser=maybe_hex::<usize>)]
+    budget: Option<usize>,
+
+    /// What to do when an SA is encrypted with an all-zero key or IV
+    #[arg(long, value_enum, default_value_t = ZeroKeyPolicy::Allow)]
+    zero_keys: ZeroKeyPolicy,
+
+    /// Output keyfile recording the SA keys used (defaults to the output with a .keys.toml extension)
+    #[arg(long, requires("random_keys"))]
+    keys_out: Option<String>,
+
+    /// SA2 compression: none, deflate, best, precompressed, or a deflate level from 0 to 9 (defaults to deflate)
+    #[arg(long, requires("sa2"))]
+    sa2_compression: Option<SACompression>,
+
+    /// Virtual address SA2 has to load at, checked against its boot header or ELF segments (optional)
+    #[arg(long, requires("sa2"), value_parser=maybe_hex::<u32>)]
+    sa2_load_address: Option<u32>,
+
+    /// RSA-2048 private key (PEM) to sign SA CmdHeads with, matching the CP cert in --certs-crls (optional)
+    #[arg(long, requires("certs_crls"))]
+    sign_key: Option<String>,
+
+    /// Certificate chain and CRLs to embed in SA headers instead of the dummy ones (optional)
+    #[arg(long, requires("sign_key"))]
+    certs_crls: Option<String>,
+
+    /// Existing NAND image to write the SKSA into; the output is then the updated image (optional)
+    #[arg(long)]
+    nand: Option<String>,
+
+    /// Spare data for the NAND image, to regenerate ECC in (optional)
+    #[arg(long, requires("nand"), conflicts_with("interleaved_spare"))]
+    spare: Option<String>,
+
+    /// Output spare data (defaults to the output with a .spare extension)
+    #[arg(long, requires("spare"))]
+    spare_out: Option<String>,
+
+    /// The NAND image has spare data stored after every page
+    #[arg(long, requires("nand"))]
+    interleaved_spare: bool,
+
+    /// Blocks to write copies of the SKSA at, for redundancy (defaults to a single copy at block 0)
+    #[arg(long, requires("nand"), value_delimiter = ',', value_parser=maybe_hex::<usize>)]
+    copy_at: Vec<usize>,
+
     /// Output BBBS SKSA
     #[arg(default_value_t = String::from("out.sksa"))]
     outfile: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Split an existing SKSA back into its SK, SA1 and SA2
+    Unpack(UnpackCli),
+
+    /// Unpack an SKSA, rebuild it and check that the result is identical
+    Verify(VerifyCli),
+
+    /// Print the layout and CmdHead fields of an SKSA (no keys required)
+    Info(InfoCli),
+
+    /// Generate a test Root -> CA -> CP certificate chain and CRLs for signing SAs
+    GenCerts(GenCertsCli),
+
+    /// Build an SKSA described by a TOML manifest
+    Manifest(ManifestCli),
+
+    /// Check that every copy of the SKSA in a NAND image is identical
+    CheckNand(CheckNandCli),
+}
+
+#[derive(clap::Args, Debug)]
+struct CheckNandCli {
+    /// Input NAND image
+    image: String,
+
+    /// Spare data for the NAND image, to find bad blocks with (optional)
+    #[arg(long, conflicts_with("interleaved_spare"))]
+    spare: Option<String>,
+
+    /// The NAND image has spare data stored after every page
+    #[arg(long)]
+    interleaved_spare: bool,
+
+    /// Blocks the copies of the SKSA start at (defaults to a single copy at block 0)
+    #[arg(long, value_delimiter = ',', value_parser=maybe_hex::<usize>)]
+    copy_at: Vec<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ManifestCli {
+    /// Input build manifest (relative paths inside it are resolved against its directory)
+    manifest: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct UnpackCli {
+    /// Input Virage2 (used for key derivation; left out when the boot app key is given instead)
+    #[arg(required = true, conflicts_with("boot_app_key"))]
+    virage2: Option<String>,
+
+    /// Input bootrom (used for key derivation; left out when the SK key and IV are given instead)
+    #[arg(required = true, conflicts_with("sk_key"))]
+    bootrom: Option<String>,
+
+    /// Input BBBS SKSA
+    infile: String,
+
+    /// Boot app key, as hex or a file holding it, instead of the Virage2
+    #[arg(long)]
+    boot_app_key: Option<String>,
+
+    /// SK encryption key, as hex or a file holding it, instead of the bootrom
+    #[arg(long, requires("sk_iv"))]
+    sk_key: Option<String>,
+
+    /// SK encryption IV, as hex or a file holding it, instead of the bootrom
+    #[arg(long, requires("sk_key"))]
+    sk_iv: Option<String>,
+
+    /// Output SK (defaults to the input with a .sk extension)
+    #[arg(long)]
+    sk: Option<String>,
+
+    /// Output SA1 (defaults to the SK output with a .sa1 extension)
+    #[arg(long)]
+    sa1: Option<String>,
+
+    /// Output SA2, if present (defaults to the SK output with a .sa2 extension)
+    #[arg(long)]
+    sa2: Option<String>,
+
+    /// Write deflated SAs out still compressed, for rebuilding with precompressed
+    #[arg(long)]
+    keep_compressed: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoCli {
+    /// Input BBBS SKSA
+    infile: String,
+
+    /// Input bootrom, to decrypt the SK and report its padding (optional)
+    #[arg(long, conflicts_with("sk_key"))]
+    bootrom: Option<String>,
+
+    /// SK encryption key, as hex or a file holding it, instead of --bootrom
+    #[arg(long, requires("sk_iv"))]
+    sk_key: Option<String>,
+
+    /// SK encryption IV, as hex or a file holding it, instead of --bootrom
+    #[arg(long, requires("sk_key"))]
+    sk_iv: Option<String>,
+
+    /// Print the layout as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct GenCertsCli {
+    /// Output certs/CRLs (for use with --certs-crls)
+    #[arg(default_value_t = String::from("certcrl.bin"))]
+    outfile: String,
+
+    /// Output CP private key (for use with --sign-key; defaults to the output with a .cp.pem extension)
+    #[arg(long)]
+    cp_key: Option<String>,
+
+    /// Output root public key (defaults to the output with a .root.pem extension)
+    #[arg(long)]
+    root_key: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyCli {
+    /// Input Virage2 (used for key derivation; left out when the boot app key is given instead)
+    #[arg(required = true, conflicts_with("boot_app_key"))]
+    virage2: Option<String>,
+
+    /// Input bootrom (used for key derivation; left out when the SK key and IV are given instead)
+    #[arg(required = true, conflicts_with("sk_key"))]
+    bootrom: Option<String>,
+
+    /// Input BBBS SKSA
+    infile: String,
+
+    /// Boot app key, as hex or a file holding it, instead of the Virage2
+    #[arg(long)]
+    boot_app_key: Option<String>,
+
+    /// SK encryption key, as hex or a file holding it, instead of the bootrom
+    #[arg(long, requires("sk_iv"))]
+    sk_key: Option<String>,
+
+    /// SK encryption IV, as hex or a file holding it, instead of the bootrom
+    #[arg(long, requires("sk_key"))]
+    sk_iv: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "CompressionSetting")]
+pub enum SACompression {
+    None,
+    /// Deflate at a fixed level, from 0 (stored blocks) to 9
+    Deflate(u32),
+    /// Deflate at whichever level gives the smallest output
+    Best,
+    /// Already a deflate stream, stored verbatim
+    Precompressed,
+}
+
+impl SACompression {
+    /// Retail SKSAs store SA1 as-is and deflate every SA after it
+    pub fn default_for(index: usize) -> Self {
+        match index {
+            0 => Self::None,
+            _ => Self::Deflate(Compression::fast().level()),
+        }
+    }
+}
+
+impl FromStr for SACompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "deflate" => Ok(Self::Deflate(Compression::fast().level())),
+            "best" => Ok(Self::Best),
+            "precompressed" => Ok(Self::Precompressed),
+            level => match level.parse() {
+                Ok(level) if level <= Compression::best().level() => Ok(Self::Deflate(level)),
+                _ => Err(format!(
+                    "expected none, deflate, best, precompressed or a deflate level from 0 to 9 (got {s})"
+                )),
+            },
+        }
+    }
+}
+
+// manifests can give a deflate level as a plain number as well as a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CompressionSetting {
+    Level(u32),
+    Name(String),
+}
+
+impl TryFrom<CompressionSetting> for SACompression {
+    type Error = String;
+
+    fn try_from(value: CompressionSetting) -> Result<Self, Self::Error> {
+        match value {
+            CompressionSetting::Level(level) => level.to_string().parse(),
+            CompressionSetting::Name(name) => name.parse(),
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct Args {
-    pub virage2: IOType,
-    pub bootrom: IOType,
-    pub sk: IOType,
-    pub sa1: IOType,
-    pub sa1_cid: u32,
-    pub sa1_key: BbAesKey,
-    pub sa1_iv: BbAesIv,
-    pub sa1_key_iv: BbAesIv,
-    pub sa2: Option<IOType>,
-    pub sa2_cid: Option<u32>,
-    pub sa2_key: Option<BbAesKey>,
-    pub sa2_iv: Option<BbAesIv>,
-    pub sa2_key_iv: Option<BbAesIv>,
-    pub outfile: IOType,
+pub struct SAArgs {
+    pub input: IOType,
+    pub cid: u32,
+    pub key: BbAesKey,
+    pub iv: BbAesIv,
+    pub key_iv: BbAesIv,
+    pub compression: SACompression,
+    /// Where the SA has to load, if it matters
+    pub load_address: Option<u32>,
 }
 
-const BLANK_KEY: BbAesKey = [0; 16];
-const BLANK_IV: BbAesIv = [0; 16];
+#[derive(Debug)]
+pub enum SpareSource {
+    None,
+    Separate(IOType),
+    Interleaved,
+}
 
-impl TryFrom<Cli> for Args {
-    type Error = hex::FromHexError;
+impl SpareSource {
+    fn new(spare: Option<String>, interleaved: bool) -> Self {
+        match (spare, interleaved) {
+            (Some(spare), _) => Self::Separate(IOType::input(spare)),
+            (None, true) => Self::Interleaved,
+            (None, false) => Self::None,
+        }
+    }
+}
 
-    fn try_from(value: Cli) -> Result<Self, Self::Error> {
-        fn replace_extension_or(orig: &Path, replace: &[&str], with: &str) -> PathBuf {
-            match orig.extension() {
-                Some(_)
-                    if replace.iter().map(OsString::from).any(|s| {
-                        s.to_ascii_lowercase() == orig.extension().unwrap().to_ascii_lowercase()
-                    }) =>
-                {
-                    orig.with_extension(with)
-                }
-                None => orig.with_extension(with),
-                _ => {
-                    let mut s = orig.as_os_str().to_owned();
-                    s.push(format!(".{with}"));
-                    s.into()
+fn copy_blocks(mut copies: Vec<usize>) -> Vec<usize> {
+    if copies.is_empty() {
+        copies.push(0);
+    }
+
+    copies.sort_unstable();
+    copies.dedup();
+
+    copies
+}
+
+#[derive(Debug)]
+pub struct NandArgs {
+    pub image: IOType,
+    pub spare: SpareSource,
+    /// Only set when the spare data is in a separate file
+    pub spare_out: Option<IOType>,
+    /// Sorted starting blocks of each copy of the SKSA
+    pub copies: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub struct CheckNandArgs {
+    pub image: IOType,
+    pub spare: SpareSource,
+    pub copies: Vec<usize>,
+}
+
+/// A key or IV given directly as hex, or a file holding it as hex or raw bytes
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    Value([u8; 16]),
+    File(IOType),
+}
+
+impl KeySource {
+    /// Anything that isn't an existing file and looks like hex has to be a valid key
+    pub(crate) fn parse_with<F: FnOnce(String) -> IOType>(
+        arg: String,
+        name: &str,
+        input: F,
+    ) -> Result<Self, MakeSKSAError> {
+        let err = match parse_hex_block(&arg, name) {
+            Ok(value) => return Ok(Self::Value(value)),
+            Err(e) => e,
+        };
+
+        match input(arg.clone()) {
+            IOType::File(path) if !path.exists() && hex_digits(&arg).is_some() => Err(err),
+            file => Ok(Self::File(file)),
+        }
+    }
+
+    fn parse(arg: String, flag: &str) -> Result<Self, MakeSKSAError> {
+        Self::parse_with(arg, flag, IOType::input)
+    }
+
+    pub fn read(&self) -> Result<[u8; 16], MakeSKSAError> {
+        match self {
+            Self::Value(value) => Ok(*value),
+            Self::File(file) => {
+                let data = file.read()?;
+
+                if let Ok(raw) = data.as_slice().try_into() {
+                    return Ok(raw);
                 }
+
+                hex_digits(&String::from_utf8_lossy(&data))
+                    .and_then(|digits| <[u8; 16]>::from_hex(digits).ok())
+                    .ok_or_else(|| MakeSKSAError::BadKeyFile(file.to_string()))
             }
         }
+    }
+}
 
-        let virage2 = IOType::input(value.virage2);
-        let bootrom = IOType::input(value.bootrom);
-        let sk = IOType::input(value.sk);
-
-        let sa1 = IOType::input(value.sa1);
-        let sa1_cid = value.sa1_cid;
-        let sa1_key = value
-            .sa1_key
-            .map(<_>::from_hex)
-            .transpose()?
-            .unwrap_or(BLANK_KEY);
-        let sa1_iv = value
-            .sa1_iv
-            .map(<_>::from_hex)
-            .transpose()?
-            .unwrap_or(BLANK_IV);
-        let sa1_key_iv = value
-            .sa1_key_iv
-            .map(<_>::from_hex)
-            .transpose()?
-            .unwrap_or(BLANK_IV);
-
-        let sa2 = value.sa2.map(IOType::input);
-        let sa2_cid = value.sa2_cid;
-        let mut sa2_key = value.sa2_key.map(<_>::from_hex).transpose()?;
-        let mut sa2_iv = value.sa2_iv.map(<_>::from_hex).transpose()?;
-        let mut sa2_key_iv = value.sa2_key_iv.map(<_>::from_hex).transpose()?;
-
-        if sa2.is_some() {
-            if sa2_key.is_none() {
-                sa2_key.replace(BLANK_KEY);
-            }
+#[derive(Debug)]
+pub enum BootAppKey {
+    Virage2(IOType),
+    Key(KeySource),
+}
 
-            if sa2_iv.is_none() {
-                sa2_iv.replace(BLANK_IV);
-            }
+impl BootAppKey {
+    // anything given on the command line wins over the profile
+    fn resolve(
+        virage2: Option<String>,
+        key: Option<String>,
+        profile: &mut NamedProfile,
+    ) -> Result<Self, MakeSKSAError> {
+        match (virage2, key, profile.profile.boot_app_key.take()) {
+            (Some(virage2), None, _) => Ok(Self::Virage2(IOType::input(virage2))),
+            (None, Some(key), _) => Ok(Self::Key(KeySource::parse(key, "--boot-app-key")?)),
+            (None, None, Some(key)) => Ok(Self::Key(KeySource::parse(
+                key,
+                &profile.name("boot_app_key"),
+            )?)),
+            _ => Err(MakeSKSAError::ExactlyOne("<VIRAGE2>", "--boot-app-key")),
+        }
+    }
 
-            if sa2_key_iv.is_none() {
-                sa2_key_iv.replace(BLANK_IV);
+    pub fn read(&self) -> Result<BbAesKey, MakeSKSAError> {
+        match self {
+            Self::Virage2(virage2) => Ok(Virage2::read_from_buf(&virage2.read()?)
+                .map_err(|e| MakeSKSAError::BadVirage2(e.into()))?
+                .boot_app_key),
+            Self::Key(key) => key.read(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SkKeys {
+    Bootrom(IOType),
+    Direct { key: KeySource, iv: KeySource },
+}
+
+impl SkKeys {
+    fn resolve(
+        bootrom: Option<String>,
+        key_iv: Option<(String, String)>,
+        profile: &mut NamedProfile,
+    ) -> Result<Self, MakeSKSAError> {
+        let from_profile = profile
+            .profile
+            .sk_key
+            .take()
+            .zip(profile.profile.sk_iv.take());
+
+        match (bootrom, key_iv, from_profile) {
+            (Some(bootrom), None, _) => Ok(Self::Bootrom(IOType::input(bootrom))),
+            (None, Some((key, iv)), _) => Ok(Self::Direct {
+                key: KeySource::parse(key, "--sk-key")?,
+                iv: KeySource::parse(iv, "--sk-iv")?,
+            }),
+            (None, None, Some((key, iv))) => Ok(Self::Direct {
+                key: KeySource::parse(key, &profile.name("sk_key"))?,
+                iv: KeySource::parse(iv, &profile.name("sk_iv"))?,
+            }),
+            _ => Err(MakeSKSAError::ExactlyOne(
+                "<BOOTROM>",
+                "--sk-key and --sk-iv",
+            )),
+        }
+    }
+
+    pub fn read(&self) -> Result<(BbAesKey, BbAesIv), MakeSKSAError> {
+        match self {
+            Self::Bootrom(bootrom) => {
+                bootrom_keys(&bootrom.read()?).map_err(|e| MakeSKSAError::BadBootrom(e.into()))
             }
+            Self::Direct { key, iv } => Ok((key.read()?, iv.read()?)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub boot_app_key: BootAppKey,
+    pub sk_keys: SkKeys,
+    pub sk: IOType,
+    pub sas: Vec<SAArgs>,
+    pub sign_key: Option<IOType>,
+    pub certs_crls: Option<IOType>,
+    pub nand: Option<NandArgs>,
+    /// Size limit in blocks
+    pub budget: Option<usize>,
+    pub zero_keys: ZeroKeyPolicy,
+    /// Where to record the SA keys, if any were drawn at random
+    pub keys_out: Option<IOType>,
+    pub outfile: IOType,
+}
+
+#[derive(Debug)]
+pub struct UnpackArgs {
+    pub boot_app_key: BootAppKey,
+    pub sk_keys: SkKeys,
+    pub infile: IOType,
+    pub sk: IOType,
+    /// Outputs for the first SAs; any further SAs are written next to the SK
+    pub sas: Vec<IOType>,
+    pub keep_compressed: bool,
+}
+
+#[derive(Debug)]
+pub struct VerifyArgs {
+    pub boot_app_key: BootAppKey,
+    pub sk_keys: SkKeys,
+    pub infile: IOType,
+}
+
+#[derive(Debug)]
+pub struct InfoArgs {
+    pub infile: IOType,
+    pub sk_keys: Option<SkKeys>,
+    pub json: bool,
+}
+
+#[derive(Debug)]
+pub struct GenCertsArgs {
+    pub outfile: IOType,
+    pub cp_key: IOType,
+    pub root_key: IOType,
+}
+
+#[derive(Debug)]
+pub enum Mode {
+    Build(Box<Args>),
+    Unpack(UnpackArgs),
+    Verify(VerifyArgs),
+    Info(InfoArgs),
+    GenCerts(GenCertsArgs),
+    CheckNand(CheckNandArgs),
+}
+
+pub(crate) const BLANK_KEY: BbAesKey = [0; 16];
+pub(crate) const BLANK_IV: BbAesIv = [0; 16];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZeroKeyPolicy {
+    #[default]
+    Allow,
+    Warn,
+    Error,
+}
+
+/// What SA keys and IVs that aren't given are filled in with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFallback {
+    Blank,
+    Random,
+}
+
+fn random_block() -> [u8; 16] {
+    let mut rv = [0; 16];
+    OsRng.fill_bytes(&mut rv);
+    rv
+}
+
+impl KeyFallback {
+    pub(crate) fn key(self) -> BbAesKey {
+        match self {
+            Self::Blank => BLANK_KEY,
+            Self::Random => random_block(),
+        }
+    }
+
+    pub(crate) fn iv(self) -> BbAesIv {
+        match self {
+            Self::Blank => BLANK_IV,
+            Self::Random => random_block(),
+        }
+    }
+}
+
+pub(crate) fn replace_extension_or(orig: &Path, replace: &[&str], with: &str) -> PathBuf {
+    match orig.extension() {
+        Some(_)
+            if replace
+                .iter()
+                .map(OsString::from)
+                .any(|s| s.eq_ignore_ascii_case(orig.extension().unwrap())) =>
+        {
+            orig.with_extension(with)
+        }
+        None => orig.with_extension(with),
+        _ => {
+            let mut s = orig.as_os_str().to_owned();
+            s.push(format!(".{with}"));
+            s.into()
+        }
+    }
+}
+
+/// Where an output that goes alongside `outfile` ends up: `given` if there is one, or else
+/// next to `outfile`, which only works if `outfile` isn't stdout
+pub(crate) fn sidecar_output<F: FnOnce(&PathBuf) -> PathBuf>(
+    given: Option<IOType>,
+    outfile: &IOType,
+    name: &str,
+    derive: F,
+) -> Result<IOType, MakeSKSAError> {
+    let output = given.unwrap_or_else(|| outfile.derive_output(derive));
+
+    if matches!((&output, outfile), (IOType::Stdout, IOType::Stdout)) {
+        return Err(MakeSKSAError::StdoutTaken(name.to_string()));
+    }
+
+    Ok(output)
+}
+
+// strips an optional `0x` prefix and any spaces or colons between the digits,
+// or returns `None` if anything else is left
+fn hex_digits(value: &str) -> Option<String> {
+    let value = value.trim();
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value)
+        .chars()
+        .filter(|&c| !c.is_whitespace() && c != ':')
+        .collect::<String>();
+
+    (!digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit())).then_some(digits)
+}
+
+/// Parses a 16-byte key or IV given as hex, naming `name` (usually the flag) in any error
+pub(crate) fn parse_hex_block(value: &str, name: &str) -> Result<[u8; 16], MakeSKSAError> {
+    let Some(digits) = hex_digits(value) else {
+        return Err(MakeSKSAError::NotHex(name.to_string(), value.to_string()));
+    };
+
+    if digits.len() != 32 {
+        return Err(MakeSKSAError::HexLength(name.to_string(), 32, digits.len()));
+    }
+
+    // already checked to be exactly 32 hex digits
+    Ok(<[u8; 16]>::from_hex(digits).unwrap())
+}
+
+/// A keyfile profile, along with where it came from so its keys can be named in errors
+#[derive(Default)]
+struct NamedProfile {
+    profile: Profile,
+    source: String,
+}
+
+impl NamedProfile {
+    fn load(keyfile: String, name: String) -> Result<Self, MakeSKSAError> {
+        let keyfile = IOType::input(keyfile);
+
+        Ok(Self {
+            profile: keyfile::load(&keyfile, &name)?,
+            source: format!("profile {name} in {keyfile}"),
+        })
+    }
+
+    fn name(&self, field: &str) -> String {
+        format!("{field} in {}", self.source)
+    }
+
+    // anything given on the command line wins over the profile
+    fn hex(
+        &self,
+        cli: Option<String>,
+        flag: &str,
+        profile: Option<String>,
+        field: &str,
+    ) -> Result<Option<[u8; 16]>, MakeSKSAError> {
+        match (cli, profile) {
+            (Some(value), _) => parse_hex_block(&value, flag).map(Some),
+            (None, Some(value)) => parse_hex_block(&value, &self.name(field)).map(Some),
+            (None, None) => Ok(None),
         }
+    }
+}
+
+impl Args {
+    // the profile is loaded before the real parse, to find out which key files are left out
+    fn new(value: Cli, mut profile: NamedProfile) -> Result<Self, MakeSKSAError> {
+        let mut profile_sas = std::mem::take(&mut profile.profile.sas).into_iter();
+        let sa1_profile = profile_sas.next().unwrap_or_default();
+        let sa2_profile = profile_sas.next().unwrap_or_default();
+
+        let fallback = if value.random_keys {
+            KeyFallback::Random
+        } else {
+            KeyFallback::Blank
+        };
+
+        let boot_app_key = BootAppKey::resolve(value.virage2, value.boot_app_key, &mut profile)?;
+        let sk_keys = SkKeys::resolve(value.bootrom, value.sk_key.zip(value.sk_iv), &mut profile)?;
+
+        // required unless there's a subcommand, which there isn't by now
+        let sk = IOType::input(value.sk.unwrap());
+
+        let mut sas = vec![SAArgs {
+            input: IOType::input(value.sa1.unwrap()),
+            cid: value.sa1_cid.unwrap(),
+            key: profile
+                .hex(value.sa1_key, "--sa1-key", sa1_profile.key, "sa[0].key")?
+                .unwrap_or_else(|| fallback.key()),
+            iv: profile
+                .hex(value.sa1_iv, "--sa1-iv", sa1_profile.iv, "sa[0].iv")?
+                .unwrap_or_else(|| fallback.iv()),
+            key_iv: profile
+                .hex(
+                    value.sa1_key_iv,
+                    "--sa1-key-iv",
+                    sa1_profile.key_iv,
+                    "sa[0].key_iv",
+                )?
+                .unwrap_or_else(|| fallback.iv()),
+            compression: value
+                .sa1_compression
+                .unwrap_or(SACompression::default_for(0)),
+            load_address: value.sa1_load_address,
+        }];
+
+        if let Some(sa2) = value.sa2 {
+            sas.push(SAArgs {
+                input: IOType::input(sa2),
+                // required by SA2
+                cid: value.sa2_cid.unwrap(),
+                key: profile
+                    .hex(value.sa2_key, "--sa2-key", sa2_profile.key, "sa[1].key")?
+                    .unwrap_or_else(|| fallback.key()),
+                iv: profile
+                    .hex(value.sa2_iv, "--sa2-iv", sa2_profile.iv, "sa[1].iv")?
+                    .unwrap_or_else(|| fallback.iv()),
+                key_iv: profile
+                    .hex(
+                        value.sa2_key_iv,
+                        "--sa2-key-iv",
+                        sa2_profile.key_iv,
+                        "sa[1].key_iv",
+                    )?
+                    .unwrap_or_else(|| fallback.iv()),
+                compression: value
+                    .sa2_compression
+                    .unwrap_or(SACompression::default_for(1)),
+                load_address: value.sa2_load_address,
+            });
+        }
+
+        let sign_key = value.sign_key.map(IOType::input);
+        let certs_crls = value.certs_crls.map(IOType::input);
 
         let outfile = IOType::output(value.outfile);
 
+        let keys_out = value
+            .random_keys
+            .then(|| {
+                sidecar_output(
+                    value.keys_out.map(IOType::output),
+                    &outfile,
+                    "--keys-out",
+                    |p| replace_extension_or(p, &["sksa"], "keys.toml"),
+                )
+            })
+            .transpose()?;
+
+        let nand = value
+            .nand
+            .map(|image| -> Result<NandArgs, MakeSKSAError> {
+                let spare = SpareSource::new(value.spare, value.interleaved_spare);
+
+                let spare_out = match spare {
+                    SpareSource::Separate(_) => Some(sidecar_output(
+                        value.spare_out.map(IOType::output),
+                        &outfile,
+                        "--spare-out",
+                        |p| replace_extension_or(p, &["bin"], "spare"),
+                    )?),
+                    _ => None,
+                };
+
+                Ok(NandArgs {
+                    image: IOType::input(image),
+                    spare,
+                    spare_out,
+                    copies: copy_blocks(value.copy_at),
+                })
+            })
+            .transpose()?;
+
         Ok(Self {
-            virage2,
-            bootrom,
+            boot_app_key,
+            sk_keys,
             sk,
-            sa1,
-            sa1_cid,
-            sa1_key,
-            sa1_iv,
-            sa1_key_iv,
-            sa2,
-            sa2_cid,
-            sa2_key,
-            sa2_iv,
-            sa2_key_iv,
+            sas,
+            sign_key,
+            certs_crls,
+            nand,
+            budget: value.budget,
+            zero_keys: value.zero_keys,
+            keys_out,
+            outfile,
+        })
+    }
+}
+
+impl TryFrom<UnpackCli> for UnpackArgs {
+    type Error = MakeSKSAError;
+
+    fn try_from(value: UnpackCli) -> Result<Self, Self::Error> {
+        let mut profile = NamedProfile::default();
+        let boot_app_key = BootAppKey::resolve(value.virage2, value.boot_app_key, &mut profile)?;
+        let sk_keys = SkKeys::resolve(value.bootrom, value.sk_key.zip(value.sk_iv), &mut profile)?;
+
+        let infile = IOType::input(value.infile);
+
+        // the SK is the main output, and the SAs go alongside it
+        let sk = match value.sk {
+            Some(p) => IOType::output(p),
+            None => infile.derive_output(|p| replace_extension_or(p, &["sksa"], "sk")),
+        };
+
+        let sas = vec![
+            sidecar_output(value.sa1.map(IOType::output), &sk, "--sa1", |p| {
+                replace_extension_or(p, &["sk"], "sa1")
+            })?,
+            sidecar_output(value.sa2.map(IOType::output), &sk, "--sa2", |p| {
+                replace_extension_or(p, &["sk"], "sa2")
+            })?,
+        ];
+
+        Ok(Self {
+            boot_app_key,
+            sk_keys,
+            infile,
+            sk,
+            sas,
+            keep_compressed: value.keep_compressed,
+        })
+    }
+}
+
+impl UnpackArgs {
+    pub fn sa_output(&self, index: usize) -> Result<IOType, MakeSKSAError> {
+        let name = format!("SA{}", index + 1);
+
+        sidecar_output(self.sas.get(index).cloned(), &self.sk, &name, |p| {
+            replace_extension_or(p, &["sk"], &name.to_ascii_lowercase())
+        })
+    }
+}
+
+impl TryFrom<VerifyCli> for VerifyArgs {
+    type Error = MakeSKSAError;
+
+    fn try_from(value: VerifyCli) -> Result<Self, Self::Error> {
+        let mut profile = NamedProfile::default();
+
+        Ok(Self {
+            boot_app_key: BootAppKey::resolve(value.virage2, value.boot_app_key, &mut profile)?,
+            sk_keys: SkKeys::resolve(value.bootrom, value.sk_key.zip(value.sk_iv), &mut profile)?,
+            infile: IOType::input(value.infile),
+        })
+    }
+}
+
+impl TryFrom<InfoCli> for InfoArgs {
+    type Error = MakeSKSAError;
+
+    fn try_from(value: InfoCli) -> Result<Self, Self::Error> {
+        let key_iv = value.sk_key.zip(value.sk_iv);
+
+        let sk_keys = match (value.bootrom, key_iv) {
+            (None, None) => None,
+            (bootrom, key_iv) => Some(SkKeys::resolve(
+                bootrom,
+                key_iv,
+                &mut NamedProfile::default(),
+            )?),
+        };
+
+        Ok(Self {
+            infile: IOType::input(value.infile),
+            sk_keys,
+            json: value.json,
+        })
+    }
+}
+
+impl TryFrom<GenCertsCli> for GenCertsArgs {
+    type Error = MakeSKSAError;
+
+    fn try_from(value: GenCertsCli) -> Result<Self, Self::Error> {
+        let outfile = IOType::output(value.outfile);
+
+        let cp_key = sidecar_output(
+            value.cp_key.map(IOType::output),
+            &outfile,
+            "--cp-key",
+            |p| replace_extension_or(p, &["bin"], "cp.pem"),
+        )?;
+        let root_key = sidecar_output(
+            value.root_key.map(IOType::output),
+            &outfile,
+            "--root-key",
+            |p| replace_extension_or(p, &["bin"], "root.pem"),
+        )?;
+
+        Ok(Self {
             outfile,
+            cp_key,
+            root_key,
+        })
+    }
+}
+
+impl From<CheckNandCli> for CheckNandArgs {
+    fn from(value: CheckNandCli) -> Self {
+        Self {
+            image: IOType::input(value.image),
+            spare: SpareSource::new(value.spare, value.interleaved_spare),
+            copies: copy_blocks(value.copy_at),
+        }
+    }
+}
+
+impl Mode {
+    fn new(mut value: Cli, profile: NamedProfile) -> Result<Self, MakeSKSAError> {
+        match value.command.take() {
+            Some(Command::Unpack(unpack)) => Ok(Self::Unpack(unpack.try_into()?)),
+            Some(Command::Verify(verify)) => Ok(Self::Verify(verify.try_into()?)),
+            Some(Command::Info(info)) => Ok(Self::Info(info.try_into()?)),
+            Some(Command::GenCerts(gen_certs)) => Ok(Self::GenCerts(gen_certs.try_into()?)),
+            Some(Command::CheckNand(check_nand)) => Ok(Self::CheckNand(check_nand.into())),
+            Some(Command::Manifest(manifest)) => Ok(Self::Build(Box::new(crate::manifest::load(
+                &manifest.manifest,
+            )?))),
+            None => Ok(Self::Build(Box::new(Args::new(value, profile)?))),
+        }
+    }
+}
+
+/// Which of the Virage2 and bootrom are left off the command line, since their keys are given
+/// some other way
+#[derive(Debug, Default, Clone, Copy)]
+struct KeysGiven {
+    boot_app_key: bool,
+    sk_keys: bool,
+}
+
+impl KeysGiven {
+    fn new(matches: &ArgMatches, profile: &Profile) -> Self {
+        let given = |id: &str| matches!(matches.try_get_one::<String>(id), Ok(Some(_)));
+
+        Self {
+            boot_app_key: given("boot_app_key") || profile.boot_app_key.is_some(),
+            sk_keys: given("sk_key") || profile.sk_key.is_some(),
+        }
+    }
+
+    // clap numbers positionals by where they are, so the ones left out are turned into (hidden)
+    // options to get them out of the way of the rest
+    fn apply(self, command: clap::Command) -> clap::Command {
+        command.mut_args(|arg| match arg.get_id().as_str() {
+            "virage2" if self.boot_app_key && arg.is_positional() => {
+                arg.long("virage2").required(false).hide(true)
+            }
+            "bootrom" if self.sk_keys && arg.is_positional() => {
+                arg.long("bootrom").required(false).hide(true)
+            }
+            _ => arg,
         })
     }
 }
 
-pub fn parse_args() -> Result<Args, hex::FromHexError> {
-    Cli::parse().try_into()
+// just reads whatever options are there, taking every value as a string so nothing stops it early
+fn lenient(command: clap::Command) -> clap::Command {
+    let relax = |arg: clap::Arg| {
+        if arg.get_action().takes_values() {
+            arg.required(false).value_parser(value_parser!(String))
+        } else {
+            arg
+        }
+    };
+
+    command
+        .ignore_errors(true)
+        .mut_args(relax)
+        .mut_subcommands(move |subcommand| subcommand.mut_args(relax))
+}
+
+fn parse_args_from(argv: Vec<OsString>) -> Result<Mode, MakeSKSAError> {
+    // the Virage2 and bootrom lead the positionals, but are left out when their keys are given
+    // with options or a profile, so those have to be looked at before the real parse
+    let found = lenient(Cli::command()).try_get_matches_from(&argv).ok();
+
+    let (profile, given) = match &found {
+        Some(matches) => match matches.subcommand() {
+            Some((_, subcommand)) => (
+                NamedProfile::default(),
+                KeysGiven::new(subcommand, &Profile::default()),
+            ),
+            None => {
+                let option = |id| matches.get_one::<String>(id).cloned();
+
+                let profile = match (option("keyfile"), option("profile")) {
+                    (Some(keyfile), Some(name)) => NamedProfile::load(keyfile, name)?,
+                    _ => NamedProfile::default(),
+                };
+                let given = KeysGiven::new(matches, &profile.profile);
+
+                (profile, given)
+            }
+        },
+        None => Default::default(),
+    };
+
+    let matches = given
+        .apply(Cli::command())
+        .mut_subcommands(|subcommand| given.apply(subcommand))
+        .get_matches_from(&argv);
+
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    Mode::new(cli, profile)
+}
+
+pub fn parse_args() -> Result<Mode, MakeSKSAError> {
+    parse_args_from(std::env::args_os().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+
+    fn parse(args: &str) -> Mode {
+        parse_args_from(args.split_whitespace().map(OsString::from).collect()).unwrap()
+    }
+
+    fn file(io: &IOType) -> &str {
+        match io {
+            IOType::File(path) => path.to_str().unwrap(),
+            other => panic!("expected a file, got {other}"),
+        }
+    }
+
+    #[test]
+    fn legacy_command_line() {
+        let Mode::Build(args) = parse("makesksa v2 boot sk sa1 0x10 sa2 0x20 out") else {
+            panic!("expected a build");
+        };
+
+        assert!(matches!(&args.boot_app_key, BootAppKey::Virage2(v) if file(v) == "v2"));
+        assert!(matches!(&args.sk_keys, SkKeys::Bootrom(b) if file(b) == "boot"));
+        assert_eq!(file(&args.sk), "sk");
+        assert_eq!(
+            args.sas
+                .iter()
+                .map(|sa| (file(&sa.input), sa.cid))
+                .collect::<Vec<_>>(),
+            [("sa1", 0x10), ("sa2", 0x20)]
+        );
+        assert_eq!(file(&args.outfile), "out");
+    }
+
+    #[test]
+    fn key_options_replace_key_files() {
+        let key = "00112233445566778899aabbccddeeff";
+
+        let Mode::Build(args) = parse(&format!(
+            "makesksa --boot-app-key {key} boot sk sa1 0x10 --sa1-key {key}"
+        )) else {
+            panic!("expected a build");
+        };
+
+        assert!(matches!(
+            args.boot_app_key,
+            BootAppKey::Key(KeySource::Value(KEY))
+        ));
+        assert!(matches!(&args.sk_keys, SkKeys::Bootrom(b) if file(b) == "boot"));
+        assert_eq!(file(&args.sk), "sk");
+        assert_eq!(args.sas[0].key, KEY);
+        assert_eq!(file(&args.outfile), "out.sksa");
+
+        let Mode::Verify(args) = parse(&format!(
+            "makesksa verify v2 in.sksa --sk-key {key} --sk-iv {key}"
+        )) else {
+            panic!("expected verify");
+        };
+
+        assert!(matches!(&args.boot_app_key, BootAppKey::Virage2(v) if file(v) == "v2"));
+        assert!(matches!(args.sk_keys, SkKeys::Direct { .. }));
+        assert_eq!(file(&args.infile), "in.sksa");
+    }
+
+    #[test]
+    fn hex_block_formats() {
+        for value in [
+            "00112233445566778899aabbccddeeff",
+            "00112233445566778899AABBCCDDEEFF",
+            "0x00112233445566778899aabbccddeeff",
+            "0X00112233445566778899AABBCCDDEEFF",
+            "00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff",
+            "00112233 44556677 8899aabb ccddeeff",
+            "  0x0011223344556677\t8899aabbccddeeff\n",
+        ] {
+            assert_eq!(parse_hex_block(value, "--key").unwrap(), KEY, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn hex_block_wrong_length() {
+        for (value, len) in [
+            ("00112233445566778899aabbccddee", 30),
+            ("00112233445566778899aabbccddeeff00", 34),
+            ("0x0", 1),
+        ] {
+            assert!(
+                matches!(
+                    parse_hex_block(value, "--key"),
+                    Err(MakeSKSAError::HexLength(ref name, 32, got)) if name == "--key" && got == len
+                ),
+                "{value:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn hex_block_not_hex() {
+        for value in [
+            "",
+            "0x",
+            "00112233445566778899aabbccddeefg",
+            "00-11-22-33-44-55-66-77-88-99-aa-bb-cc-dd-ee-ff",
+            "key.bin",
+        ] {
+            assert!(
+                matches!(
+                    parse_hex_block(value, "--key"),
+                    Err(MakeSKSAError::NotHex(ref name, ref given)) if name == "--key" && given == value
+                ),
+                "{value:?}"
+            );
+        }
+    }
 }
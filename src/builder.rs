@@ -0,0 +1,349 @@
+use bb::{BbAesIv, BbAesKey, CmdHead, Virage2, BLOCK_SIZE};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Decompress, FlushDecompress, Status};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use sha1::{Digest, Sha1};
+use soft_aes::aes::aes_enc_cbc;
+
+use std::io::Write;
+
+use crate::args::SACompression;
+use crate::{certs, sign, MakeSKSAError, SKSAComponent, DUMMY_CERTS_CRLS, SK_SIZE};
+
+#[derive(Debug, Clone)]
+pub struct SAEntry {
+    pub data: Vec<u8>,
+    pub cid: u32,
+    pub key: BbAesKey,
+    pub iv: BbAesIv,
+    pub key_iv: BbAesIv,
+    pub compression: SACompression,
+}
+
+fn deflate(
+    data: &[u8],
+    level: Compression,
+    component: SKSAComponent,
+) -> Result<Vec<u8>, MakeSKSAError> {
+    let mut encoder = DeflateEncoder::new(vec![], level);
+    encoder
+        .write_all(data)
+        .map_err(|e| MakeSKSAError::Deflate(component, e))?;
+
+    encoder
+        .finish()
+        .map_err(|e| MakeSKSAError::Deflate(component, e))
+}
+
+/// Inflates a raw deflate stream, which has to run to its end and be followed by nothing but padding
+pub(crate) fn inflate(data: &[u8], component: SKSAComponent) -> Result<Vec<u8>, MakeSKSAError> {
+    let bad = |reason: String| MakeSKSAError::BadDeflateStream(component, reason);
+
+    let mut decompress = Decompress::new(false);
+    let mut inflated = vec![];
+
+    // the read-based decoders stop quietly when the input runs out, so drive this by hand
+    loop {
+        let progress = (decompress.total_in(), decompress.total_out());
+
+        inflated.reserve(BLOCK_SIZE);
+        let status = decompress
+            .decompress_vec(
+                &data[decompress.total_in() as usize..],
+                &mut inflated,
+                FlushDecompress::None,
+            )
+            .map_err(|e| bad(e.to_string()))?;
+
+        if status == Status::StreamEnd {
+            break;
+        }
+
+        if (decompress.total_in(), decompress.total_out()) == progress {
+            return Err(bad(String::from("stream is truncated")));
+        }
+    }
+
+    // anything after the end of the stream has to be padding
+    if data[decompress.total_in() as usize..]
+        .iter()
+        .any(|&b| b != 0)
+    {
+        return Err(bad(String::from(
+            "trailing data after the end of the stream",
+        )));
+    }
+
+    Ok(inflated)
+}
+
+fn check_deflate(data: &[u8], component: SKSAComponent) -> Result<(), MakeSKSAError> {
+    inflate(data, component).map(|_| ())
+}
+
+/// Builds an SKSA entirely in memory
+#[derive(Debug, Clone)]
+pub struct SksaBuilder {
+    boot_app_key: BbAesKey,
+    sk_key: BbAesKey,
+    sk_iv: BbAesIv,
+    sk: Vec<u8>,
+    /// Each SA, with the existing CmdHead block it's rebuilt from, if any
+    sas: Vec<(SAEntry, Option<Vec<u8>>)>,
+    sign_key: Option<RsaPrivateKey>,
+    /// The dummy ones are used if these aren't given, but only when not signing
+    certs_crls: Option<Vec<u8>>,
+}
+
+impl SksaBuilder {
+    pub fn new(boot_app_key: BbAesKey, sk_key: BbAesKey, sk_iv: BbAesIv) -> Self {
+        Self {
+            boot_app_key,
+            sk_key,
+            sk_iv,
+            sk: vec![],
+            sas: vec![],
+            sign_key: None,
+            certs_crls: None,
+        }
+    }
+
+    /// Takes the boot app key from a full Virage2 dump
+    pub fn from_virage2(
+        virage2: &[u8],
+        sk_key: BbAesKey,
+        sk_iv: BbAesIv,
+    ) -> Result<Self, MakeSKSAError> {
+        let virage2 =
+            Virage2::read_from_buf(virage2).map_err(|e| MakeSKSAError::BadVirage2(e.into()))?;
+
+        Ok(Self::new(virage2.boot_app_key, sk_key, sk_iv))
+    }
+
+    pub fn sk(mut self, sk: &[u8]) -> Self {
+        self.sk = sk.to_vec();
+        self
+    }
+
+    /// SAs are appended after the SK in the order they're added
+    pub fn sa(mut self, sa: SAEntry) -> Self {
+        self.sas.push((sa, None));
+        self
+    }
+
+    /// Adds an SA rebuilt from an existing CmdHead block, keeping its hash, signature and
+    /// certs/CRLs
+    pub(crate) fn sa_from_cmd(mut self, sa: SAEntry, cmd_block: &[u8]) -> Self {
+        self.sas.push((sa, Some(cmd_block.to_vec())));
+        self
+    }
+
+    pub fn sign_key(mut self, key: RsaPrivateKey) -> Self {
+        self.sign_key = Some(key);
+        self
+    }
+
+    /// Required when signing, since they have to hold the signing key's CP cert
+    pub fn certs_crls(mut self, certs_crls: &[u8]) -> Self {
+        self.certs_crls = Some(certs_crls.to_vec());
+        self
+    }
+
+    fn make_cmd_block(
+        &self,
+        mut cmd: CmdHead,
+        data: &[u8],
+        certs_crls: &[u8],
+        component: SKSAComponent,
+    ) -> Result<Vec<u8>, MakeSKSAError> {
+        if let Some(key) = &self.sign_key {
+            // both are covered by the signature, so they have to be filled in first
+            cmd.hash.copy_from_slice(&Sha1::digest(data));
+            cmd.issuer = certs::cp_issuer(certs_crls).ok_or(MakeSKSAError::NoCpCert)?;
+
+            sign::sign_cmd(&mut cmd, key, component)?;
+
+            // nothing could check the signature otherwise
+            let modulus = certs::cp_modulus(certs_crls).ok_or(MakeSKSAError::NoCpCert)?;
+            if modulus != key.n().to_bytes_be() {
+                return Err(MakeSKSAError::CpCertMismatch);
+            }
+        }
+
+        let mut block = cmd
+            .to_buf()
+            .map_err(|e| MakeSKSAError::BadCmdHead(component, e.into()))?;
+
+        if block.len() + certs_crls.len() > BLOCK_SIZE {
+            return Err(MakeSKSAError::CertsCrlsTooLong(
+                certs_crls.len(),
+                BLOCK_SIZE - block.len(),
+            ));
+        }
+
+        block.extend(certs_crls);
+        block.resize(BLOCK_SIZE, 0);
+
+        Ok(block)
+    }
+
+    fn prepare_sa(sa: &SAEntry, component: SKSAComponent) -> Result<Vec<u8>, MakeSKSAError> {
+        let mut data = match sa.compression {
+            SACompression::None => sa.data.clone(),
+            SACompression::Deflate(level) => deflate(&sa.data, Compression::new(level), component)?,
+            // the highest level isn't always the smallest, so try them all
+            SACompression::Best => {
+                let mut best = deflate(&sa.data, Compression::best(), component)?;
+
+                for level in 0..Compression::best().level() {
+                    let data = deflate(&sa.data, Compression::new(level), component)?;
+                    if data.len() < best.len() {
+                        best = data;
+                    }
+                }
+
+                best
+            }
+            SACompression::Precompressed => {
+                check_deflate(&sa.data, component)?;
+                sa.data.clone()
+            }
+        };
+
+        if data.len() > u32::MAX as _ {
+            return Err(MakeSKSAError::ComponentTooLong(
+                component,
+                data.len(),
+                u32::MAX as _,
+            ));
+        }
+
+        data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+
+        Ok(data)
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, MakeSKSAError> {
+        if self.sk.len() > SK_SIZE {
+            return Err(MakeSKSAError::ComponentTooLong(
+                SKSAComponent::Sk,
+                self.sk.len(),
+                SK_SIZE,
+            ));
+        }
+
+        if self.sas.is_empty() {
+            return Err(MakeSKSAError::NoSAs);
+        }
+
+        let certs_crls = match (&self.certs_crls, &self.sign_key) {
+            (Some(certs_crls), _) => certs_crls.as_slice(),
+            (None, None) => DUMMY_CERTS_CRLS,
+            (None, Some(_)) => return Err(MakeSKSAError::NoCertsCrls),
+        };
+
+        let mut sk = self.sk.clone();
+        sk.resize(SK_SIZE, 0);
+
+        let sk = aes_enc_cbc(&sk, &self.sk_key, &self.sk_iv, None)
+            .map_err(|e| MakeSKSAError::Encryption(SKSAComponent::Sk, e.to_string().into()))?;
+
+        let mut outfile = vec![];
+
+        outfile.extend(sk);
+
+        for (index, (sa, original_cmd)) in self.sas.iter().enumerate() {
+            let component = SKSAComponent::Sa(index + 1);
+            let data = Self::prepare_sa(sa, component)?;
+
+            let fresh = CmdHead::new_unsigned(
+                sa.key,
+                sa.iv,
+                self.boot_app_key,
+                sa.key_iv,
+                data.len() as _,
+                sa.cid,
+            );
+
+            // only the fields that come from the SA's keys and body are redone for an existing
+            // CmdHead, so its hash, signature and anything else it carries are kept as they were
+            let (cmd, certs_crls) = match original_cmd {
+                Some(block) => {
+                    let mut cmd = CmdHead::read_from_buf(block)
+                        .map_err(|e| MakeSKSAError::BadCmdHead(component, e.into()))?;
+                    let head_len = cmd
+                        .to_buf()
+                        .map_err(|e| MakeSKSAError::BadCmdHead(component, e.into()))?
+                        .len();
+
+                    cmd.key = fresh.key;
+                    cmd.iv = fresh.iv;
+                    cmd.common_cmd_iv = fresh.common_cmd_iv;
+                    cmd.size = fresh.size;
+                    cmd.content_id = fresh.content_id;
+
+                    (cmd, &block[head_len..])
+                }
+                None => (fresh, certs_crls),
+            };
+
+            outfile.extend(self.make_cmd_block(cmd, &data, certs_crls, component)?);
+            outfile.extend(
+                aes_enc_cbc(&data, &sa.key, &sa.iv, None)
+                    .map_err(|e| MakeSKSAError::Encryption(component, e.to_string().into()))?,
+            );
+        }
+
+        Ok(outfile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPONENT: SKSAComponent = SKSAComponent::Sa(2);
+
+    fn stream() -> (Vec<u8>, Vec<u8>) {
+        let data = b"some SA data that deflates well ".repeat(0x100);
+        let deflated = deflate(&data, Compression::best(), COMPONENT).unwrap();
+
+        (data, deflated)
+    }
+
+    #[test]
+    fn inflate_accepts_padding() {
+        let (data, mut deflated) = stream();
+        assert_eq!(inflate(&deflated, COMPONENT).unwrap(), data);
+
+        deflated.resize(deflated.len().next_multiple_of(BLOCK_SIZE), 0);
+        assert_eq!(inflate(&deflated, COMPONENT).unwrap(), data);
+    }
+
+    #[test]
+    fn inflate_rejects_truncated_stream() {
+        let (_, deflated) = stream();
+
+        for len in [0, 1, deflated.len() / 2, deflated.len() - 1] {
+            assert!(
+                matches!(
+                    inflate(&deflated[..len], COMPONENT),
+                    Err(MakeSKSAError::BadDeflateStream(COMPONENT, _))
+                ),
+                "{len}"
+            );
+        }
+    }
+
+    #[test]
+    fn inflate_rejects_trailing_data() {
+        let (_, mut deflated) = stream();
+        deflated.extend([0, 0, 0x5A, 0]);
+
+        assert!(matches!(
+            inflate(&deflated, COMPONENT),
+            Err(MakeSKSAError::BadDeflateStream(COMPONENT, reason)) if reason.contains("trailing")
+        ));
+    }
+}
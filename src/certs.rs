@@ -0,0 +1,196 @@
+use rand::rngs::OsRng;
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha1::{Digest, Sha1};
+
+use crate::MakeSKSAError;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// layout of certcrl.bin: CP cert, CA cert, a reserved gap, then the CP CRL
+const CERT_SIZE: usize = 0x390;
+const RESERVED_SIZE: usize = 0x54;
+const GENERIC_SIG_SIZE: usize = 0x200;
+const SERVER_NAME_SIZE: usize = 0x40;
+
+// a cert starts with its type, signature type and date, then the issuer and its own name
+const CERT_ISSUER_OFFSET: usize = 12;
+const CERT_NAME_OFFSET: usize = CERT_ISSUER_OFFSET + SERVER_NAME_SIZE;
+const CERT_KEY_OFFSET: usize = CERT_NAME_OFFSET + SERVER_NAME_SIZE;
+
+// CP certs always hold an RSA-2048 key
+const CP_MODULUS_SIZE: usize = 0x100;
+
+const CERT_TYPE_RSA: u32 = 1;
+const CRL_TYPE_CP: u32 = 1;
+const CRL_VERSION: u32 = 1;
+
+const ROOT_NAME: &str = "Root";
+const CA_NAME: &str = "CA00000001";
+const CP_NAME: &str = "CP00000002";
+
+#[derive(Debug, Clone, Copy)]
+enum SigType {
+    Rsa2048 = 0,
+    Rsa4096 = 1,
+}
+
+impl SigType {
+    fn bits(self) -> usize {
+        match self {
+            Self::Rsa2048 => 2048,
+            Self::Rsa4096 => 4096,
+        }
+    }
+}
+
+pub struct TestChain {
+    pub root: RsaPrivateKey,
+    pub ca: RsaPrivateKey,
+    pub cp: RsaPrivateKey,
+    pub certs_crls: Vec<u8>,
+}
+
+fn server_name(name: &str) -> [u8; SERVER_NAME_SIZE] {
+    let mut rv = [0; SERVER_NAME_SIZE];
+    rv[..name.len()].copy_from_slice(name.as_bytes());
+    rv
+}
+
+fn read_server_name(buf: &[u8]) -> Option<&str> {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    std::str::from_utf8(&buf[..len])
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// The issuer a CmdHead signed by the chain's CP names, e.g. `Root-CA00000001-CP00000002`
+pub fn cp_issuer(certs_crls: &[u8]) -> Option<[u8; SERVER_NAME_SIZE]> {
+    let cp_cert = certs_crls.get(..CERT_SIZE)?;
+
+    let issuer = read_server_name(&cp_cert[CERT_ISSUER_OFFSET..CERT_NAME_OFFSET])?;
+    let name = read_server_name(&cp_cert[CERT_NAME_OFFSET..CERT_NAME_OFFSET + SERVER_NAME_SIZE])?;
+
+    let issuer = format!("{issuer}-{name}");
+    (issuer.len() < SERVER_NAME_SIZE).then(|| server_name(&issuer))
+}
+
+/// The modulus of the CP cert's public key, which has to match whatever key signs with it
+pub fn cp_modulus(certs_crls: &[u8]) -> Option<&[u8]> {
+    certs_crls
+        .get(..CERT_SIZE)
+        .map(|cp_cert| &cp_cert[CERT_KEY_OFFSET..][..CP_MODULUS_SIZE])
+}
+
+fn sign(signer: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>, MakeSKSAError> {
+    let hash = Sha1::digest(data);
+
+    let mut signature = signer
+        .sign(Pkcs1v15Sign::new::<Sha1>(), &hash)
+        .map_err(MakeSKSAError::CertChain)?;
+    signature.resize(GENERIC_SIG_SIZE, 0);
+
+    Ok(signature)
+}
+
+fn make_cert(
+    issuer: &str,
+    name: &str,
+    date: u32,
+    key: &RsaPublicKey,
+    sig_type: SigType,
+    signer: &RsaPrivateKey,
+) -> Result<Vec<u8>, MakeSKSAError> {
+    let mut cert = vec![];
+
+    cert.extend(CERT_TYPE_RSA.to_be_bytes());
+    cert.extend((sig_type as u32).to_be_bytes());
+    cert.extend(date.to_be_bytes());
+    cert.extend(server_name(issuer));
+    cert.extend(server_name(name));
+
+    let modulus = key.n().to_bytes_be();
+    cert.resize(cert.len() + key.size() - modulus.len(), 0);
+    cert.extend(modulus);
+
+    let exponent = key.e().to_bytes_be();
+    cert.resize(cert.len() + 4 - exponent.len(), 0);
+    cert.extend(exponent);
+
+    let signature = sign(signer, &cert)?;
+    cert.extend(signature);
+
+    cert.resize(CERT_SIZE, 0);
+
+    Ok(cert)
+}
+
+fn make_crl(
+    issuer: &str,
+    date: u32,
+    sig_type: SigType,
+    signer: &RsaPrivateKey,
+) -> Result<Vec<u8>, MakeSKSAError> {
+    let mut head = vec![];
+
+    head.extend(CRL_TYPE_CP.to_be_bytes());
+    head.extend((sig_type as u32).to_be_bytes());
+    head.extend(0u32.to_be_bytes());
+    head.extend(CRL_VERSION.to_be_bytes());
+    head.extend(date.to_be_bytes());
+    head.extend(server_name(issuer));
+    // no revoked entries
+    head.extend(0u32.to_be_bytes());
+
+    let mut crl = sign(signer, &head)?;
+    crl.extend(head);
+
+    Ok(crl)
+}
+
+pub fn generate() -> Result<TestChain, MakeSKSAError> {
+    let key = |sig_type: SigType| {
+        RsaPrivateKey::new(&mut OsRng, sig_type.bits()).map_err(MakeSKSAError::CertChain)
+    };
+
+    let root = key(SigType::Rsa4096)?;
+    let ca = key(SigType::Rsa2048)?;
+    let cp = key(SigType::Rsa2048)?;
+
+    let date = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as u32);
+
+    let ca_issuer = format!("{ROOT_NAME}-{CA_NAME}");
+
+    let cp_cert = make_cert(
+        &ca_issuer,
+        CP_NAME,
+        date,
+        &cp.to_public_key(),
+        SigType::Rsa2048,
+        &ca,
+    )?;
+    let ca_cert = make_cert(
+        ROOT_NAME,
+        CA_NAME,
+        date,
+        &ca.to_public_key(),
+        SigType::Rsa4096,
+        &root,
+    )?;
+    let cp_crl = make_crl(&ca_issuer, date, SigType::Rsa2048, &ca)?;
+
+    let mut certs_crls = vec![];
+    certs_crls.extend(cp_cert);
+    certs_crls.extend(ca_cert);
+    certs_crls.resize(certs_crls.len() + RESERVED_SIZE, 0);
+    certs_crls.extend(cp_crl);
+
+    Ok(TestChain {
+        root,
+        ca,
+        cp,
+        certs_crls,
+    })
+}
@@ -0,0 +1,292 @@
+use std::ops::Range;
+
+use crate::{MakeSKSAError, SKSAComponent};
+
+const MAGIC: &[u8] = b"\x7FELF";
+
+const CLASS_32: u8 = 1;
+const DATA_BIG_ENDIAN: u8 = 2;
+const MACHINE_MIPS: u16 = 8;
+
+const HEADER_SIZE: usize = 0x34;
+const PROGRAM_HEADER_SIZE: usize = 0x20;
+
+const PT_LOAD: u32 = 1;
+
+pub fn is_elf(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    /// Where the segment runs (`p_vaddr`), which is what it's placed by
+    pub address: u32,
+    pub data: &'a [u8],
+    /// Includes any zero-filled space after `data`
+    pub mem_size: u32,
+}
+
+impl Segment<'_> {
+    fn range(&self) -> Range<u64> {
+        self.address as u64..self.address as u64 + self.mem_size as u64
+    }
+}
+
+/// Just enough of a 32-bit big-endian MIPS ELF to flatten its loadable segments
+#[derive(Debug)]
+pub struct Elf<'a> {
+    pub entry: u32,
+    pub segments: Vec<Segment<'a>>,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..][..2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..][..4].try_into().unwrap())
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(buf: &'a [u8], component: SKSAComponent) -> Result<Self, MakeSKSAError> {
+        let bad = |reason: &str| MakeSKSAError::BadElf(component, reason.to_string());
+
+        if buf.len() < HEADER_SIZE || !is_elf(buf) {
+            return Err(bad("not an ELF file"));
+        }
+
+        if buf[4] != CLASS_32 || buf[5] != DATA_BIG_ENDIAN {
+            return Err(bad("not a 32-bit big-endian ELF"));
+        }
+
+        if read_u16(buf, 0x12) != MACHINE_MIPS {
+            return Err(bad("not a MIPS ELF"));
+        }
+
+        let entry = read_u32(buf, 0x18);
+        let phoff = read_u32(buf, 0x1C) as usize;
+        let phentsize = read_u16(buf, 0x2A) as usize;
+        let phnum = read_u16(buf, 0x2C) as usize;
+
+        if phentsize < PROGRAM_HEADER_SIZE || phoff + phentsize * phnum > buf.len() {
+            return Err(bad("program headers are out of bounds"));
+        }
+
+        let mut segments = vec![];
+
+        for index in 0..phnum {
+            let ph = &buf[phoff + index * phentsize..][..PROGRAM_HEADER_SIZE];
+
+            if read_u32(ph, 0x00) != PT_LOAD {
+                continue;
+            }
+
+            let offset = read_u32(ph, 0x04) as usize;
+            let address = read_u32(ph, 0x08);
+            let file_size = read_u32(ph, 0x10) as usize;
+            let mem_size = read_u32(ph, 0x14);
+
+            if offset + file_size > buf.len() {
+                return Err(bad("segment data is out of bounds"));
+            }
+
+            if mem_size == 0 {
+                continue;
+            }
+
+            segments.push(Segment {
+                address,
+                data: &buf[offset..][..file_size],
+                mem_size: mem_size.max(file_size as u32),
+            });
+        }
+
+        if segments.is_empty() {
+            return Err(bad("no loadable segments"));
+        }
+
+        segments.sort_by_key(|s| s.address);
+
+        Ok(Self { entry, segments })
+    }
+
+    /// Lays the segments out as one flat image by virtual address, starting at the lowest,
+    /// refusing segments that overlap or fall outside `range`; unlike `objcopy -O binary`,
+    /// which goes by the LMA, this follows the VMA, since that's where the code has to run
+    pub fn to_flat(
+        &self,
+        component: SKSAComponent,
+        range: Range<u32>,
+    ) -> Result<(u32, Vec<u8>), MakeSKSAError> {
+        let allowed = range.start as u64..range.end as u64;
+
+        for segment in &self.segments {
+            let r = segment.range();
+            if r.start < allowed.start || r.end > allowed.end {
+                return Err(MakeSKSAError::ElfOutOfRange(
+                    component,
+                    segment.address,
+                    range.start,
+                    range.end,
+                ));
+            }
+        }
+
+        // sorted by address, so only neighbours can overlap
+        for pair in self.segments.windows(2) {
+            if pair[0].range().end > pair[1].range().start {
+                return Err(MakeSKSAError::ElfOverlap(
+                    component,
+                    pair[0].address,
+                    pair[1].address,
+                ));
+            }
+        }
+
+        // segments with nothing but zero-filled space aren't part of the image
+        let base = self
+            .segments
+            .iter()
+            .find(|s| !s.data.is_empty())
+            .unwrap_or(&self.segments[0])
+            .address;
+
+        let mut flat = vec![];
+        for segment in self.segments.iter().filter(|s| !s.data.is_empty()) {
+            let start = (segment.address - base) as usize;
+            flat.resize(start, 0);
+            flat.extend(segment.data);
+        }
+
+        Ok((base, flat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPONENT: SKSAComponent = SKSAComponent::Sa(1);
+    const RANGE: Range<u32> = 0x8000_0000..0x8001_0000;
+
+    /// A minimal ELF with one PT_LOAD program header per `(address, data, mem_size)`
+    fn make_elf(entry: u32, segments: &[(u32, &[u8], u32)]) -> Vec<u8> {
+        let mut buf = vec![0; HEADER_SIZE];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4] = CLASS_32;
+        buf[5] = DATA_BIG_ENDIAN;
+        buf[0x12..0x14].copy_from_slice(&MACHINE_MIPS.to_be_bytes());
+        buf[0x18..0x1C].copy_from_slice(&entry.to_be_bytes());
+        buf[0x1C..0x20].copy_from_slice(&(HEADER_SIZE as u32).to_be_bytes());
+        buf[0x2A..0x2C].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_be_bytes());
+        buf[0x2C..0x2E].copy_from_slice(&(segments.len() as u16).to_be_bytes());
+
+        let mut offset = HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE;
+        for &(address, data, mem_size) in segments {
+            let mut ph = [0; PROGRAM_HEADER_SIZE];
+            ph[0x00..0x04].copy_from_slice(&PT_LOAD.to_be_bytes());
+            ph[0x04..0x08].copy_from_slice(&(offset as u32).to_be_bytes());
+            ph[0x08..0x0C].copy_from_slice(&address.to_be_bytes());
+            ph[0x10..0x14].copy_from_slice(&(data.len() as u32).to_be_bytes());
+            ph[0x14..0x18].copy_from_slice(&mem_size.to_be_bytes());
+            buf.extend(ph);
+            offset += data.len();
+        }
+
+        for &(_, data, _) in segments {
+            buf.extend(data);
+        }
+
+        buf
+    }
+
+    fn flatten(buf: &[u8]) -> Result<(u32, Vec<u8>), MakeSKSAError> {
+        Elf::parse(buf, COMPONENT)?.to_flat(COMPONENT, RANGE)
+    }
+
+    #[test]
+    fn flattens_in_address_order() {
+        let buf = make_elf(
+            0x8000_0000,
+            &[
+                (0x8000_0008, &[3, 4], 2),
+                (0x8000_0000, &[1, 2], 2),
+                (0x8000_0010, &[], 0x100),
+            ],
+        );
+
+        assert_eq!(
+            flatten(&buf).unwrap(),
+            (0x8000_0000, vec![1, 2, 0, 0, 0, 0, 0, 0, 3, 4])
+        );
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let buf = make_elf(
+            0x8000_0000,
+            &[(0x8000_0000, &[0; 8], 8), (0x8000_0004, &[0; 8], 8)],
+        );
+        assert!(matches!(
+            flatten(&buf),
+            Err(MakeSKSAError::ElfOverlap(
+                COMPONENT,
+                0x8000_0000,
+                0x8000_0004
+            ))
+        ));
+
+        // zero-filled space counts too
+        let buf = make_elf(
+            0x8000_0000,
+            &[(0x8000_0000, &[0; 4], 0x10), (0x8000_0008, &[0; 4], 4)],
+        );
+        assert!(matches!(
+            flatten(&buf),
+            Err(MakeSKSAError::ElfOverlap(
+                COMPONENT,
+                0x8000_0000,
+                0x8000_0008
+            ))
+        ));
+
+        // touching isn't overlapping
+        let buf = make_elf(
+            0x8000_0000,
+            &[(0x8000_0000, &[0; 4], 4), (0x8000_0004, &[0; 4], 4)],
+        );
+        assert!(flatten(&buf).is_ok());
+    }
+
+    #[test]
+    fn rejects_out_of_range_segments() {
+        let buf = make_elf(0x8000_0000, &[(0x7FFF_FFFC, &[0; 8], 8)]);
+        assert!(matches!(
+            flatten(&buf),
+            Err(MakeSKSAError::ElfOutOfRange(
+                COMPONENT,
+                0x7FFF_FFFC,
+                0x8000_0000,
+                0x8001_0000
+            ))
+        ));
+
+        // running past the end, counting zero-filled space
+        let buf = make_elf(0x8000_0000, &[(0x8000_FFF0, &[0; 8], 0x20)]);
+        assert!(matches!(
+            flatten(&buf),
+            Err(MakeSKSAError::ElfOutOfRange(COMPONENT, 0x8000_FFF0, _, _))
+        ));
+
+        // ending exactly at the end of the range is fine
+        let buf = make_elf(0x8000_0000, &[(0x8000_FFF0, &[0; 8], 0x10)]);
+        assert!(flatten(&buf).is_ok());
+
+        // and segments up at the top of the address space can't wrap around
+        let buf = make_elf(0x8000_0000, &[(0xFFFF_FFF0, &[0; 8], 0x20)]);
+        assert!(matches!(
+            flatten(&buf),
+            Err(MakeSKSAError::ElfOutOfRange(COMPONENT, 0xFFFF_FFF0, _, _))
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+use crate::args::{IOType, SAArgs};
+use crate::MakeSKSAError;
+
+/// Name of the single profile in keyfiles written by `save`
+pub const BUILD_PROFILE: &str = "build";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSA {
+    pub key: Option<String>,
+    pub iv: Option<String>,
+    pub key_iv: Option<String>,
+}
+
+/// One console's key set; anything left out falls back to the command line or the defaults
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_app_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sk_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sk_iv: Option<String>,
+    /// Keys for SA1, SA2, ... in order
+    #[serde(default, rename = "sa")]
+    pub sas: Vec<ProfileSA>,
+}
+
+/// Keyfiles are TOML, unless they have a .json extension
+pub fn load(keyfile: &IOType, name: &str) -> Result<Profile, MakeSKSAError> {
+    let text = keyfile.read_string()?;
+
+    let bad = |e: String| MakeSKSAError::BadConfig(keyfile.to_string(), e);
+
+    let mut profiles: HashMap<String, Profile> = match keyfile {
+        IOType::File(path)
+            if path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("json")) =>
+        {
+            serde_json::from_str(&text).map_err(|e| bad(e.to_string()))?
+        }
+        _ => toml::from_str(&text).map_err(|e| bad(e.to_string()))?,
+    };
+
+    let profile = profiles
+        .remove(name)
+        .ok_or_else(|| MakeSKSAError::NoSuchProfile(name.to_string(), keyfile.to_string()))?;
+
+    if profile.sk_key.is_some() != profile.sk_iv.is_some() {
+        return Err(MakeSKSAError::IncompleteProfile(name.to_string()));
+    }
+
+    Ok(profile)
+}
+
+/// Records the SA keys an SKSA was built with as a TOML keyfile holding a single profile
+pub fn save(name: &str, sas: &[SAArgs]) -> Result<String, MakeSKSAError> {
+    let profile = Profile {
+        sas: sas
+            .iter()
+            .map(|sa| ProfileSA {
+                key: Some(hex::encode(sa.key)),
+                iv: Some(hex::encode(sa.iv)),
+                key_iv: Some(hex::encode(sa.key_iv)),
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    toml::to_string(&HashMap::from([(name, profile)]))
+        .map_err(|e| MakeSKSAError::BadConfig(String::from("keyfile"), e.to_string()))
+}
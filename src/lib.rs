@@ -1,37 +1,48 @@
-use anyhow::Result;
-use bb::{bootrom_keys, BbAesIv, BbAesKey, BbShaHash, CmdHead, HashHex, Virage2, BLOCK_SIZE};
-use flate2::write::DeflateEncoder;
-use flate2::Compression;
-use soft_aes::aes::aes_enc_cbc;
+use bb::{BbAesIv, BbAesKey, BLOCK_SIZE};
+use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+use soft_aes::aes::aes_dec_cbc;
 use thiserror::Error;
 
 use std::fmt::Display;
-use std::io::Write;
+use std::ops::Range;
 
 pub mod args;
-
-use args::Args;
+pub mod builder;
+pub mod certs;
+pub mod elf;
+pub mod keyfile;
+pub mod manifest;
+pub mod mips;
+pub mod nand;
+pub mod sign;
+pub mod sksa;
+
+use args::{
+    Args, CheckNandArgs, GenCertsArgs, IOType, InfoArgs, NandArgs, SACompression, SpareSource,
+    UnpackArgs, VerifyArgs, ZeroKeyPolicy,
+};
+use elf::Elf;
+use mips::SK_LOAD_ADDRESS;
+use nand::NandImage;
+use sksa::{SALayout, SKSARegion, SKSA};
+
+pub use builder::{SAEntry, SksaBuilder};
 
 const SK_SIZE: usize = 64 * 1024;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SKSAComponent {
     Sk,
-    Sa1,
-    Sa2,
+    /// SAs are numbered from 1, in the order they appear after the SK
+    Sa(usize),
 }
 
 impl Display for SKSAComponent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Self::Sk => "SK",
-                Self::Sa1 => "SA1",
-                Self::Sa2 => "SA2",
-            }
-        )
+        match self {
+            Self::Sk => write!(f, "SK"),
+            Self::Sa(n) => write!(f, "SA{n}"),
+        }
     }
 }
 
@@ -39,117 +50,676 @@ impl Display for SKSAComponent {
 pub enum MakeSKSAError {
     #[error("Provided {0} is too long (got 0x{1:X} bytes, max 0x{2:X})")]
     ComponentTooLong(SKSAComponent, usize, usize),
+    #[error("Provided SKSA is truncated ({0} needs 0x{1:X} bytes, only 0x{2:X} remain)")]
+    Truncated(SKSAComponent, usize, usize),
+    #[error("Rebuilt SKSA does not match the original")]
+    RebuildMismatch,
+    #[error("Signing key must be 2048-bit RSA (got {0} bits)")]
+    BadSigningKey(usize),
+    #[error("Provided certs/CRLs are too long (got 0x{0:X} bytes, max 0x{1:X})")]
+    CertsCrlsTooLong(usize, usize),
+    #[error("At least one SA is required")]
+    NoSAs,
+    #[error("NAND image size 0x{0:X} is not a multiple of 0x{1:X}")]
+    BadNandSize(usize, usize),
+    #[error("Spare data is the wrong size (got 0x{0:X} bytes, expected 0x{1:X})")]
+    BadSpareSize(usize, usize),
+    #[error(
+        "SKSA does not fit in the NAND image (needs {0} blocks, only {1} good blocks available)"
+    )]
+    NandTooSmall(usize, usize),
+    #[error("SKSA copy at block {0} runs into the copy at block {1}")]
+    CopiesOverlap(usize, usize),
+    #[error("Not every copy of the SKSA is present and identical")]
+    CopiesDiffer,
+    #[error("{0} does not hold a 16-byte key (as hex or raw bytes)")]
+    BadKeyFile(String),
+    #[error("Give exactly one of {0} or {1}")]
+    ExactlyOne(&'static str, &'static str),
+    #[error("No profile named {0} in {1}")]
+    NoSuchProfile(String, String),
+    #[error("Profile {0} must give both sk_key and sk_iv, or neither")]
+    IncompleteProfile(String),
+    #[error("{0} is encrypted with an all-zero {1}")]
+    ZeroKey(SKSAComponent, &'static str),
+    #[error("{0} is not a clean deflate stream ({1})")]
+    BadDeflateStream(SKSAComponent, String),
+    #[error("SKSA needs {0} blocks, over the budget of {1}")]
+    OverBudget(usize, usize),
+    #[error("{0} is not a usable ELF ({1})")]
+    BadElf(SKSAComponent, String),
+    #[error("{0} has a segment at 0x{1:08X}, outside 0x{2:08X}-0x{3:08X}")]
+    ElfOutOfRange(SKSAComponent, u32, u32, u32),
+    #[error("{0} has overlapping segments at 0x{1:08X} and 0x{2:08X}")]
+    ElfOverlap(SKSAComponent, u32, u32),
+    #[error("{0} loads at virtual address 0x{1:08X}, expected 0x{2:08X}")]
+    WrongLoadAddress(SKSAComponent, u32, u32),
+    #[error("{0} has a bad boot header ({1})")]
+    BadSaHeader(SKSAComponent, String),
+    #[error("{0} entry point 0x{1:08X} is outside 0x{2:08X}-0x{3:08X}")]
+    EntryOutOfRange(SKSAComponent, u32, u32, u32),
+    #[error("{0} entry point is 0x{1:08X}, expected 0x{2:08X}")]
+    WrongEntry(SKSAComponent, u32, u32),
+    #[error("{0} ({1})")]
+    Io(#[source] std::io::Error, String),
+    #[error("{0} is not hex: {1:?}")]
+    NotHex(String, String),
+    #[error("{0} should be {1} hex digits, got {2}")]
+    HexLength(String, usize, usize),
+    #[error("Couldn't parse Virage2: {0}")]
+    BadVirage2(#[source] Cause),
+    #[error("Couldn't get the SK key and IV from the bootrom: {0}")]
+    BadBootrom(#[source] Cause),
+    #[error("Couldn't encrypt {0}: {1}")]
+    Encryption(SKSAComponent, #[source] Cause),
+    #[error("Couldn't decrypt {0}: {1}")]
+    Decryption(SKSAComponent, #[source] Cause),
+    #[error("Bad {0} CmdHead: {1}")]
+    BadCmdHead(SKSAComponent, #[source] Cause),
+    #[error("Couldn't deflate {0}: {1}")]
+    Deflate(SKSAComponent, #[source] std::io::Error),
+    #[error("Couldn't read signing key: {0}")]
+    BadSigningKeyPem(#[source] rsa::pkcs1::Error),
+    #[error("Couldn't sign {0} CmdHead: {1}")]
+    Signing(SKSAComponent, #[source] rsa::Error),
+    #[error("Can't sign without a CP cert at the start of the certs/CRLs")]
+    NoCpCert,
+    #[error("Signing key doesn't match the CP cert in the certs/CRLs")]
+    CpCertMismatch,
+    #[error("Signing needs the certs/CRLs holding the key's CP cert")]
+    NoCertsCrls,
+    #[error("Bad {0}: {1}")]
+    BadConfig(String, String),
+    #[error("{0} requires {1}")]
+    FieldRequires(&'static str, &'static str),
+    #[error("The output is going to stdout, so {0} has to go somewhere else")]
+    StdoutTaken(String),
+    #[error("Couldn't generate the cert chain: {0}")]
+    CertChain(#[source] rsa::Error),
+    #[error("Couldn't write the {0} key as PEM: {1}")]
+    KeyPem(&'static str, #[source] rsa::pkcs8::Error),
+    #[error("Couldn't write the layout as JSON: {0}")]
+    Json(#[source] serde_json::Error),
 }
 
+/// The cause of an error from `bb` or soft-aes, which don't share an error type
+/// (soft-aes errors aren't `Send`, so those only keep their message)
+pub type Cause = Box<dyn std::error::Error + Send + Sync>;
+
 // horrible hack so emoose's iQueTool code doesn't die on these SKSA blobs
 // eventually I'll write a replacement and this won't be necessary
+// (`gen-certs` can produce a real chain in the same layout, but this is still the default)
 const DUMMY_CERTS_CRLS: &[u8] = include_bytes!("certcrl.bin");
 
-pub fn build(args: Args) -> Result<()> {
-    let virage2 = args.virage2.read()?;
-    let virage2 = Virage2::read_from_buf(&virage2)?;
+fn check_zero_keys(args: &Args) -> Result<(), MakeSKSAError> {
+    if args.zero_keys == ZeroKeyPolicy::Allow {
+        return Ok(());
+    }
+
+    for (index, sa) in args.sas.iter().enumerate() {
+        for (name, value) in [("key", sa.key), ("IV", sa.iv), ("key IV", sa.key_iv)] {
+            if value.iter().any(|&b| b != 0) {
+                continue;
+            }
+
+            let err = MakeSKSAError::ZeroKey(SKSAComponent::Sa(index + 1), name);
+
+            if args.zero_keys == ZeroKeyPolicy::Error {
+                return Err(err);
+            }
 
-    let bootrom = args.bootrom.read()?;
+            eprintln!("Warning: {err}");
+        }
+    }
 
-    let (sk_key, sk_iv) = bootrom_keys(&bootrom)?;
+    Ok(())
+}
 
-    let mut sk = args.sk.read()?;
+fn check_budget(sksa: &[u8], budget: usize) -> Result<(), MakeSKSAError> {
+    let mut used = 0;
 
-    if sk.len() > SK_SIZE {
-        return Err(MakeSKSAError::ComponentTooLong(SKSAComponent::Sk, sk.len(), SK_SIZE).into());
+    for (region, data) in SKSA::parse(sksa)?.regions() {
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        eprintln!("{region}: {blocks} blocks");
+        used += blocks;
     }
 
-    if sk.len() < SK_SIZE {
-        sk.resize(SK_SIZE, 0);
+    eprintln!("Total: {used} of {budget} blocks");
+
+    if used > budget {
+        return Err(MakeSKSAError::OverBudget(used, budget));
     }
 
-    let mut sa1 = args.sa1.read()?;
+    Ok(())
+}
 
-    if sa1.len() > u32::MAX as _ {
-        return Err(
-            MakeSKSAError::ComponentTooLong(SKSAComponent::Sa1, sa1.len(), u32::MAX as _).into(),
-        );
+pub fn build(args: Args) -> Result<(), MakeSKSAError> {
+    check_zero_keys(&args)?;
+
+    let outfile = make_sksa(&args)?;
+
+    if let Some(budget) = args.budget {
+        check_budget(&outfile, budget)?;
+    }
+
+    // the keys go first, so there's never an SKSA around without a record of them
+    if let Some(keys_out) = &args.keys_out {
+        keys_out.write(keyfile::save(keyfile::BUILD_PROFILE, &args.sas)?)?;
+    }
+
+    match &args.nand {
+        Some(nand) => write_nand(nand, &outfile, &args.outfile)?,
+        None => {
+            args.outfile.write(outfile)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_nand(image: &IOType, spare: &SpareSource) -> Result<NandImage, MakeSKSAError> {
+    let image = image.read()?;
+
+    match spare {
+        SpareSource::None => NandImage::new(image, None),
+        SpareSource::Separate(spare) => NandImage::new(image, Some(spare.read()?)),
+        SpareSource::Interleaved => NandImage::from_interleaved(&image),
+    }
+}
+
+fn write_nand(args: &NandArgs, sksa: &[u8], outfile: &IOType) -> Result<(), MakeSKSAError> {
+    let mut nand = read_nand(&args.image, &args.spare)?;
+
+    let regions = SKSA::parse(sksa)?.regions();
+
+    for copy in nand.write_copies(sksa, &args.copies)? {
+        // the report goes to stderr, since the image itself might be going to stdout
+        eprintln!("SKSA copy at block {}:", copy.start);
+
+        let bad = (copy.start..=*copy.map.last().unwrap())
+            .filter(|&b| nand.is_bad_block(b))
+            .collect::<Vec<_>>();
+        if !bad.is_empty() {
+            eprintln!("  Skipped bad blocks: {bad:?}");
+        }
+
+        if !copy.erased.is_empty() {
+            eprintln!("  Erased stale blocks: {:?}", copy.erased);
+        }
+
+        let mut blocks = copy.map.iter().enumerate();
+        for (region, data) in &regions {
+            for (logical, physical) in blocks.by_ref().take(data.len().div_ceil(BLOCK_SIZE)) {
+                eprintln!("  Block {logical} ({region}) -> NAND block {physical}");
+            }
+        }
+    }
+
+    match &args.spare {
+        SpareSource::None => {
+            outfile.write(nand.data)?;
+        }
+        SpareSource::Separate(_) => {
+            outfile.write(&nand.data)?;
+            // always set alongside separate spare data
+            args.spare_out
+                .as_ref()
+                .unwrap()
+                .write(nand.spare.unwrap())?;
+        }
+        SpareSource::Interleaved => {
+            outfile.write(nand.to_interleaved())?;
+        }
     }
 
-    sa1.resize(sa1.len().next_multiple_of(BLOCK_SIZE), 0);
+    Ok(())
+}
+
+/// Reads an SK or SA input, flattening it first if it's an ELF (in which case its entry point is returned too)
+fn read_image(
+    input: &IOType,
+    component: SKSAComponent,
+    range: Range<u32>,
+    load_address: Option<u32>,
+) -> Result<(Vec<u8>, Option<u32>), MakeSKSAError> {
+    let data = input.read()?;
+
+    if !elf::is_elf(&data) {
+        return Ok((data, None));
+    }
 
-    let sa2 = args
-        .sa2
-        .map(|f| -> Result<Vec<u8>> {
-            let sa2 = f.read()?;
+    let elf = Elf::parse(&data, component)?;
+    let (base, flat) = elf.to_flat(component, range)?;
 
-            let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
-            encoder.write_all(&sa2)?;
-            let mut sa2 = encoder.finish()?;
+    if let Some(expected) = load_address {
+        if base != expected {
+            return Err(MakeSKSAError::WrongLoadAddress(component, base, expected));
+        }
+    }
+
+    eprintln!(
+        "{component}: converted ELF to 0x{:X} bytes loading at 0x{base:08X}",
+        flat.len()
+    );
 
-            if sa2.len() > u32::MAX as _ {
-                return Err(MakeSKSAError::ComponentTooLong(
-                    SKSAComponent::Sa2,
-                    sa2.len(),
-                    u32::MAX as _,
-                )
-                .into());
+    Ok((flat, Some(elf.entry)))
+}
+
+fn make_sksa(args: &Args) -> Result<Vec<u8>, MakeSKSAError> {
+    let (sk_key, sk_iv) = args.sk_keys.read()?;
+
+    let (sk, entry) = read_image(
+        &args.sk,
+        SKSAComponent::Sk,
+        SK_LOAD_ADDRESS..SK_LOAD_ADDRESS + SK_SIZE as u32,
+        Some(SK_LOAD_ADDRESS),
+    )?;
+
+    // the bootrom jumps to the start of the SK, whatever the ELF says
+    if let Some(entry) = entry {
+        if entry != SK_LOAD_ADDRESS {
+            return Err(MakeSKSAError::WrongEntry(
+                SKSAComponent::Sk,
+                entry,
+                SK_LOAD_ADDRESS,
+            ));
+        }
+    }
+
+    let mut builder =
+        SksaBuilder::new(args.boot_app_key.read()?, sk_key, sk_iv).sk(&mips::check_sk(&sk)?);
+
+    for (index, sa) in args.sas.iter().enumerate() {
+        let component = SKSAComponent::Sa(index + 1);
+
+        // a precompressed stream is never an ELF, and its header can't be checked without inflating it
+        let data = match sa.compression {
+            SACompression::Precompressed => sa.input.read()?,
+            _ => {
+                let (data, entry) =
+                    read_image(&sa.input, component, mips::SA_RANGE, sa.load_address)?;
+
+                match entry {
+                    // the load address was already checked against the ELF's segments
+                    Some(entry) => mips::check_sa_entry(entry, component, None)?,
+                    None => mips::check_sa_entry(
+                        mips::sa_entry(&data, component)?,
+                        component,
+                        sa.load_address,
+                    )?,
+                }
+
+                data
             }
+        };
+
+        builder = builder.sa(SAEntry {
+            data,
+            cid: sa.cid,
+            key: sa.key,
+            iv: sa.iv,
+            key_iv: sa.key_iv,
+            compression: sa.compression,
+        });
+    }
 
-            sa2.resize(sa2.len().next_multiple_of(BLOCK_SIZE), 0);
+    if let Some(sign_key) = &args.sign_key {
+        builder = builder.sign_key(sign::read_key(&sign_key.read_string()?)?);
+    }
+
+    if let Some(certs_crls) = &args.certs_crls {
+        builder = builder.certs_crls(&certs_crls.read()?);
+    }
 
-            Ok(sa2)
+    builder.build()
+}
+
+struct ExtractedSA {
+    data: Vec<u8>,
+    /// What the SA was found to be: `Precompressed` if it's a deflate stream, otherwise `None`
+    compression: SACompression,
+}
+
+struct Components {
+    sk: Vec<u8>,
+    sas: Vec<ExtractedSA>,
+}
+
+fn extract(
+    sksa: &SKSA,
+    sk_key: &BbAesKey,
+    sk_iv: &BbAesIv,
+    common_key: &BbAesKey,
+    inflate: bool,
+) -> Result<Components, MakeSKSAError> {
+    let sk = aes_dec_cbc(sksa.sk, sk_key, sk_iv, None)
+        .map_err(|e| MakeSKSAError::Decryption(SKSAComponent::Sk, e.to_string().into()))?;
+
+    let sas = sksa
+        .sas
+        .iter()
+        .enumerate()
+        .map(|(index, sa)| -> Result<ExtractedSA, MakeSKSAError> {
+            let data = sa.decrypt(common_key)?;
+
+            // nothing in the SKSA says whether an SA is compressed, so see if it inflates
+            Ok(
+                match builder::inflate(&data, SKSAComponent::Sa(index + 1)) {
+                    Ok(inflated) => ExtractedSA {
+                        data: if inflate { inflated } else { data },
+                        compression: SACompression::Precompressed,
+                    },
+                    Err(_) => ExtractedSA {
+                        data,
+                        compression: SACompression::None,
+                    },
+                },
+            )
         })
-        .transpose()?;
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Components { sk, sas })
+}
+
+pub fn unpack(args: UnpackArgs) -> Result<(), MakeSKSAError> {
+    let boot_app_key = args.boot_app_key.read()?;
+    let (sk_key, sk_iv) = args.sk_keys.read()?;
+
+    let sksa = args.infile.read()?;
+    let sksa = SKSA::parse_prefix(&sksa)?;
+
+    let components = extract(&sksa, &sk_key, &sk_iv, &boot_app_key, !args.keep_compressed)?;
+
+    // every output is worked out first, so nothing is written if one of them can't be
+    let outputs = (0..components.sas.len())
+        .map(|index| args.sa_output(index))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    args.sk.write(components.sk)?;
+    for (output, sa) in outputs.iter().zip(components.sas) {
+        output.write(sa.data)?;
+    }
+
+    Ok(())
+}
+
+pub fn verify(args: VerifyArgs) -> Result<(), MakeSKSAError> {
+    let boot_app_key = args.boot_app_key.read()?;
+    let (sk_key, sk_iv) = args.sk_keys.read()?;
+
+    let original = args.infile.read()?;
+    let sksa = SKSA::parse_prefix(&original)?;
+    // any blank blocks after the last SA are just unused space, not part of the SKSA
+    let original = &original[..sksa.size()];
+
+    // deflated SAs are passed through as-is, since flate2 won't reproduce the original streams
+    let components = extract(&sksa, &sk_key, &sk_iv, &boot_app_key, false)?;
+
+    // the recovered images go straight to the builder, since the build-time checks are about
+    // catching mistakes in new inputs, not reporting on what's already in the SKSA
+    let mut builder = SksaBuilder::new(boot_app_key, sk_key, sk_iv).sk(&components.sk);
+
+    for (sa, extracted) in sksa.sas.iter().zip(components.sas) {
+        builder = builder.sa_from_cmd(
+            SAEntry {
+                data: extracted.data,
+                cid: sa.cmd.content_id,
+                key: sa.title_key(&boot_app_key)?,
+                iv: sa.cmd.iv,
+                key_iv: sa.cmd.common_cmd_iv,
+                compression: extracted.compression,
+            },
+            sa.cmd_block,
+        );
+    }
+
+    let rebuilt = builder.build()?;
+
+    if rebuilt == original {
+        println!("Rebuilt SKSA matches {} byte-for-byte", args.infile);
+        return Ok(());
+    }
 
-    let sk = aes_enc_cbc(&sk, &sk_key, &sk_iv, None).expect("encryption failed");
+    let original_regions = sksa.regions();
+    let rebuilt_regions = SKSA::parse(&rebuilt)?.regions();
 
-    let sa1_cmd = CmdHead::new_unsigned(
-        args.sa1_key,
-        args.sa1_iv,
-        virage2.boot_app_key,
-        args.sa1_key_iv,
-        sa1.len() as _,
-        args.sa1_cid,
+    let mut mismatched = original_regions
+        .iter()
+        .filter(|(region, data)| {
+            !rebuilt_regions
+                .iter()
+                .any(|(r, d)| r == region && d == data)
+        })
+        .map(|(region, _)| *region)
+        .collect::<Vec<SKSARegion>>();
+
+    mismatched.extend(
+        rebuilt_regions
+            .iter()
+            .filter(|(region, _)| !original_regions.iter().any(|(r, _)| r == region))
+            .map(|(region, _)| *region),
     );
 
-    let mut sa1_cmd = sa1_cmd.to_buf()?;
-    sa1_cmd.extend(DUMMY_CERTS_CRLS);
-    sa1_cmd.resize(BLOCK_SIZE, 0);
+    if mismatched.is_empty() {
+        println!(
+            "Rebuilt SKSA differs in length (got 0x{:X} bytes, expected 0x{:X})",
+            rebuilt.len(),
+            original.len()
+        );
+    }
+
+    for region in mismatched {
+        println!("{region} differs");
+    }
 
-    let sa1 = aes_enc_cbc(&sa1, &args.sa1_key, &args.sa1_iv, None).expect("encryption failed");
+    Err(MakeSKSAError::RebuildMismatch)
+}
 
-    let sa2_cmd = sa2
+fn print_sa(sa: &SALayout) {
+    println!(
+        "{} header at 0x{:X} (block {}):",
+        sa.name, sa.header_offset, sa.header_block
+    );
+    println!("  Content ID:    0x{:08X}", sa.content_id);
+    println!("  Size:          0x{:X}", sa.body_size);
+    println!("  Key:           {}", sa.key);
+    println!("  IV:            {}", sa.iv);
+    println!("  Common CMD IV: {}", sa.common_cmd_iv);
+    println!("  Hash:          {}", sa.hash);
+    println!("  Signature:     {}", sa.signature);
+    println!(
+        "  Certs/CRLs:    0x{:X} bytes, SHA-1 {}{}",
+        sa.certs_crls_size,
+        sa.certs_crls_sha1,
+        if sa.dummy_certs_crls { " (dummy)" } else { "" }
+    );
+    println!(
+        "{} body at 0x{:X} (block {}): 0x{:X} bytes",
+        sa.name, sa.body_offset, sa.body_block, sa.body_size
+    );
+}
+
+pub fn info(args: InfoArgs) -> Result<(), MakeSKSAError> {
+    let sksa = args.infile.read()?;
+    let sksa = SKSA::parse_prefix(&sksa)?;
+
+    let sk = args
+        .sk_keys
         .as_ref()
-        .map(|sa| -> Result<Vec<u8>> {
-            let cmd = CmdHead::new_unsigned(
-                args.sa2_key.unwrap(),
-                args.sa2_iv.unwrap(),
-                virage2.boot_app_key,
-                args.sa2_key_iv.unwrap(),
-                sa.len() as _,
-                args.sa2_cid.unwrap(),
-            );
-
-            let mut cmd = cmd.to_buf()?;
-            cmd.extend(DUMMY_CERTS_CRLS);
-            cmd.resize(BLOCK_SIZE, 0);
-
-            Ok(cmd)
+        .map(|sk_keys| -> Result<Vec<u8>, MakeSKSAError> {
+            let (sk_key, sk_iv) = sk_keys.read()?;
+
+            aes_dec_cbc(sksa.sk, &sk_key, &sk_iv, None)
+                .map_err(|e| MakeSKSAError::Decryption(SKSAComponent::Sk, e.to_string().into()))
         })
         .transpose()?;
 
-    let sa2 = sa2.map(|sa| {
-        aes_enc_cbc(&sa, &args.sa2_key.unwrap(), &args.sa2_iv.unwrap(), None)
-            .expect("encryption failed")
-    });
+    let layout = sksa.layout(sk.as_deref())?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&layout).map_err(MakeSKSAError::Json)?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    println!(
+        "SK at 0x{:X}: 0x{:X} bytes, SHA-1 (encrypted) {}",
+        layout.sk.offset, layout.sk.size, layout.sk.sha1
+    );
+    if let Some(code_size) = layout.sk.code_size {
+        println!(
+            "  Code size 0x{code_size:X}, 0x{:X} bytes of padding",
+            layout.sk.size - code_size
+        );
+    }
+
+    for sa in &layout.sas {
+        print_sa(sa);
+    }
+
+    Ok(())
+}
+
+pub fn check_nand(args: CheckNandArgs) -> Result<(), MakeSKSAError> {
+    let nand = read_nand(&args.image, &args.spare)?;
 
-    let mut outfile = vec![];
+    let mut copies = vec![];
 
-    outfile.extend(sk);
-    outfile.extend(sa1_cmd);
-    outfile.extend(sa1);
-    if let Some(sa2) = sa2 {
-        outfile.extend(sa2_cmd.unwrap());
-        outfile.extend(sa2);
+    for (index, &start) in args.copies.iter().enumerate() {
+        let end = args.copies.get(index + 1).copied().unwrap_or(nand.blocks());
+        let (data, map) = nand.read_skipping_bad(start, end);
+
+        match SKSA::parse_prefix(&data) {
+            Ok(sksa) => {
+                let size = sksa.size();
+                println!(
+                    "Copy at block {start}: 0x{size:X} bytes in NAND blocks {:?}",
+                    &map[..size.div_ceil(BLOCK_SIZE)]
+                );
+                copies.push((start, data[..size].to_vec()));
+            }
+            Err(e) => println!("Copy at block {start}: unreadable ({e})"),
+        }
     }
 
-    args.outfile.write(outfile)?;
+    // the bootrom takes the first copy it can read, so that's what everything is compared against
+    let Some((first, reference)) = copies.first() else {
+        return Err(MakeSKSAError::CopiesDiffer);
+    };
+
+    println!("Bootrom would use the copy at block {first}");
+
+    let mut identical = copies.len() == args.copies.len();
+    for (start, data) in &copies[1..] {
+        if data != reference {
+            println!("Copy at block {start} differs from the copy at block {first}");
+            identical = false;
+        }
+    }
+
+    if !identical {
+        return Err(MakeSKSAError::CopiesDiffer);
+    }
+
+    println!("All {} copies are identical", copies.len());
+
+    Ok(())
+}
+
+pub fn gen_certs(args: GenCertsArgs) -> Result<(), MakeSKSAError> {
+    let chain = certs::generate()?;
+
+    let cp_key = chain
+        .cp
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| MakeSKSAError::KeyPem("CP", e))?;
+    let root_key = chain
+        .root
+        .to_public_key()
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| MakeSKSAError::KeyPem("root", e.into()))?;
+
+    args.outfile.write(chain.certs_crls)?;
+    args.cp_key.write(cp_key.as_bytes())?;
+    args.root_key.write(root_key)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOT_APP_KEY: BbAesKey = [0x11; 16];
+    const SK_KEY: BbAesKey = [0x22; 16];
+    const SK_IV: BbAesIv = [0x33; 16];
+
+    fn padded(data: &[u8], size: usize) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data.resize(size, 0);
+        data
+    }
+
+    #[test]
+    fn builder_round_trip() {
+        let sk = (0..0x1234).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        // starts with a stored block whose length check fails, so it can't pass for deflate
+        let sa1 = (0..0x6000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let sa2 = b"a nice compressible SA2 ".repeat(0x400);
+
+        let sksa = SksaBuilder::new(BOOT_APP_KEY, SK_KEY, SK_IV)
+            .sk(&sk)
+            .sa(SAEntry {
+                data: sa1.clone(),
+                cid: 0x1234,
+                key: [0x44; 16],
+                iv: [0x55; 16],
+                key_iv: [0x66; 16],
+                compression: SACompression::None,
+            })
+            .sa(SAEntry {
+                data: sa2.clone(),
+                cid: 0x5678,
+                key: [0x77; 16],
+                iv: [0x88; 16],
+                key_iv: [0x99; 16],
+                compression: SACompression::Best,
+            })
+            .build()
+            .unwrap();
+
+        let parsed = SKSA::parse(&sksa).unwrap();
+        assert_eq!(parsed.size(), sksa.len());
+        assert_eq!(parsed.sas.len(), 2);
+        assert_eq!(parsed.sas[0].cmd.content_id, 0x1234);
+        assert_eq!(parsed.sas[1].cmd.content_id, 0x5678);
+        assert_eq!(parsed.sas[0].title_key(&BOOT_APP_KEY).unwrap(), [0x44; 16]);
+        assert_eq!(parsed.sas[1].title_key(&BOOT_APP_KEY).unwrap(), [0x77; 16]);
+        assert!(parsed.sas[0]
+            .certs_crls()
+            .unwrap()
+            .starts_with(DUMMY_CERTS_CRLS));
+
+        let components = extract(&parsed, &SK_KEY, &SK_IV, &BOOT_APP_KEY, true).unwrap();
+        assert_eq!(components.sk, padded(&sk, SK_SIZE));
+
+        let [extracted1, extracted2] = &components.sas[..] else {
+            panic!("expected two SAs, got {}", components.sas.len());
+        };
+
+        assert_eq!(extracted1.data, padded(&sa1, 2 * BLOCK_SIZE));
+        assert!(matches!(extracted1.compression, SACompression::None));
+
+        assert_eq!(extracted2.data, sa2);
+        assert!(matches!(
+            extracted2.compression,
+            SACompression::Precompressed
+        ));
+
+        // a blank block after the last SA is only accepted as trailing space
+        let mut trailing = sksa.clone();
+        trailing.resize(sksa.len() + BLOCK_SIZE, 0xFF);
+        assert!(SKSA::parse(&trailing).is_err());
+        assert_eq!(SKSA::parse_prefix(&trailing).unwrap().size(), sksa.len());
+    }
+}
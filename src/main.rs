@@ -1,7 +1,13 @@
 use anyhow::Result;
+use makesksa::args::Mode;
 
 fn main() -> Result<()> {
-    let args = makesksa::args::parse_args()?;
-
-    makesksa::build(args)
+    match makesksa::args::parse_args()? {
+        Mode::Build(args) => Ok(makesksa::build(*args)?),
+        Mode::Unpack(args) => Ok(makesksa::unpack(args)?),
+        Mode::Verify(args) => Ok(makesksa::verify(args)?),
+        Mode::Info(args) => Ok(makesksa::info(args)?),
+        Mode::GenCerts(args) => Ok(makesksa::gen_certs(args)?),
+        Mode::CheckNand(args) => Ok(makesksa::check_nand(args)?),
+    }
 }
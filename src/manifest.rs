@@ -0,0 +1,203 @@
+use bb::{BbAesIv, BbAesKey};
+use serde::Deserialize;
+
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::args::{
+    parse_hex_block, replace_extension_or, sidecar_output, Args, BootAppKey, IOType, KeyFallback,
+    KeySource, SAArgs, SACompression, SkKeys, ZeroKeyPolicy,
+};
+use crate::MakeSKSAError;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestSA {
+    pub path: String,
+    pub cid: u32,
+    pub key: Option<String>,
+    pub iv: Option<String>,
+    pub key_iv: Option<String>,
+    /// `none`, `deflate`, `best`, `precompressed`, or a deflate level (as a number or a string)
+    pub compression: Option<SACompression>,
+    /// Virtual address, checked against the SA's boot header, or its segments if `path` is an ELF
+    pub load_address: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub virage2: Option<String>,
+    /// Hex, or a file holding the key; an alternative to `virage2`
+    pub boot_app_key: Option<String>,
+    pub bootrom: Option<String>,
+    /// Hex, or files holding the key and IV; an alternative to `bootrom`
+    pub sk_key: Option<String>,
+    pub sk_iv: Option<String>,
+    pub sk: String,
+    #[serde(rename = "sa")]
+    pub sas: Vec<ManifestSA>,
+    pub sign_key: Option<String>,
+    pub certs_crls: Option<String>,
+    /// Draw any SA keys and IVs not given at random, recording them in `keys_out`
+    #[serde(default)]
+    pub random_keys: bool,
+    pub keys_out: Option<String>,
+    /// Size limit in blocks
+    pub budget: Option<usize>,
+    #[serde(default)]
+    pub zero_keys: ZeroKeyPolicy,
+    #[serde(default = "default_outfile")]
+    pub outfile: String,
+}
+
+fn default_outfile() -> String {
+    String::from("out.sksa")
+}
+
+struct Resolver<'a>(&'a Path);
+
+impl Resolver<'_> {
+    fn input(&self, path: String) -> IOType {
+        match path.as_str() {
+            "-" => IOType::Stdin,
+            p => IOType::File(self.0.join(p)),
+        }
+    }
+
+    fn output(&self, path: String) -> IOType {
+        match path.as_str() {
+            "-" => IOType::Stdout,
+            p => IOType::File(self.0.join(p)),
+        }
+    }
+}
+
+struct SAKeys {
+    key: BbAesKey,
+    iv: BbAesIv,
+    key_iv: BbAesIv,
+}
+
+impl ManifestSA {
+    fn keys(&self, index: usize, fallback: KeyFallback) -> Result<SAKeys, MakeSKSAError> {
+        let hex = |value: &Option<String>, field: &str| {
+            value
+                .as_ref()
+                .map(|v| parse_hex_block(v, &format!("sa[{index}].{field}")))
+                .transpose()
+        };
+
+        Ok(SAKeys {
+            key: hex(&self.key, "key")?.unwrap_or_else(|| fallback.key()),
+            iv: hex(&self.iv, "iv")?.unwrap_or_else(|| fallback.iv()),
+            key_iv: hex(&self.key_iv, "key_iv")?.unwrap_or_else(|| fallback.iv()),
+        })
+    }
+}
+
+impl Manifest {
+    pub fn read(path: &Path) -> Result<Self, MakeSKSAError> {
+        let manifest =
+            read_to_string(path).map_err(|e| MakeSKSAError::Io(e, path.display().to_string()))?;
+
+        toml::from_str(&manifest)
+            .map_err(|e| MakeSKSAError::BadConfig(path.display().to_string(), e.to_string()))
+    }
+
+    /// Relative paths are resolved against `base`, usually the manifest's directory
+    pub fn into_args(self, base: &Path) -> Result<Args, MakeSKSAError> {
+        let resolver = Resolver(base);
+
+        if self.sas.is_empty() {
+            return Err(MakeSKSAError::NoSAs);
+        }
+
+        // the same as the command line, where clap checks these
+        if self.keys_out.is_some() && !self.random_keys {
+            return Err(MakeSKSAError::FieldRequires("keys_out", "random_keys"));
+        }
+
+        if self.sign_key.is_some() && self.certs_crls.is_none() {
+            return Err(MakeSKSAError::FieldRequires("sign_key", "certs_crls"));
+        }
+
+        let boot_app_key = match (self.virage2, self.boot_app_key) {
+            (Some(virage2), None) => BootAppKey::Virage2(resolver.input(virage2)),
+            (None, Some(key)) => {
+                BootAppKey::Key(KeySource::parse_with(key, "boot_app_key", |p| {
+                    resolver.input(p)
+                })?)
+            }
+            _ => return Err(MakeSKSAError::ExactlyOne("virage2", "boot_app_key")),
+        };
+
+        let sk_keys = match (self.bootrom, self.sk_key, self.sk_iv) {
+            (Some(bootrom), None, None) => SkKeys::Bootrom(resolver.input(bootrom)),
+            (None, Some(key), Some(iv)) => SkKeys::Direct {
+                key: KeySource::parse_with(key, "sk_key", |p| resolver.input(p))?,
+                iv: KeySource::parse_with(iv, "sk_iv", |p| resolver.input(p))?,
+            },
+            _ => return Err(MakeSKSAError::ExactlyOne("bootrom", "sk_key and sk_iv")),
+        };
+
+        let fallback = if self.random_keys {
+            KeyFallback::Random
+        } else {
+            KeyFallback::Blank
+        };
+
+        let sas = self
+            .sas
+            .into_iter()
+            .enumerate()
+            .map(|(index, sa)| -> Result<SAArgs, MakeSKSAError> {
+                let keys = sa.keys(index, fallback)?;
+
+                Ok(SAArgs {
+                    input: resolver.input(sa.path),
+                    cid: sa.cid,
+                    key: keys.key,
+                    iv: keys.iv,
+                    key_iv: keys.key_iv,
+                    compression: sa.compression.unwrap_or(SACompression::default_for(index)),
+                    load_address: sa.load_address,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let outfile = resolver.output(self.outfile);
+
+        let keys_out = self
+            .random_keys
+            .then(|| {
+                sidecar_output(
+                    self.keys_out.map(|p| resolver.output(p)),
+                    &outfile,
+                    "keys_out",
+                    |p| replace_extension_or(p, &["sksa"], "keys.toml"),
+                )
+            })
+            .transpose()?;
+
+        Ok(Args {
+            boot_app_key,
+            sk_keys,
+            sk: resolver.input(self.sk),
+            sas,
+            sign_key: self.sign_key.map(|p| resolver.input(p)),
+            certs_crls: self.certs_crls.map(|p| resolver.input(p)),
+            nand: None,
+            budget: self.budget,
+            zero_keys: self.zero_keys,
+            keys_out,
+            outfile,
+        })
+    }
+}
+
+pub fn load(path: &Path) -> Result<Args, MakeSKSAError> {
+    let base = path.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+
+    Manifest::read(path)?.into_args(&base)
+}
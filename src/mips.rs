@@ -0,0 +1,143 @@
+use std::ops::Range;
+
+use crate::{MakeSKSAError, SKSAComponent, SK_SIZE};
+
+/// The bootrom copies the SK to the start of internal SRAM and jumps straight there
+pub const SK_LOAD_ADDRESS: u32 = 0x9FC4_0000;
+
+/// KSEG0 view of the iQue Player's 16 MiB of RDRAM
+pub const RAM: Range<u32> = 0x8000_0000..0x8100_0000;
+
+/// System apps load above the exception vectors
+pub const SA_RANGE: Range<u32> = 0x8000_0400..RAM.end;
+
+const INSTRUCTION_SIZE: usize = 4;
+
+// system apps start with an N64-style cartridge header
+const SA_HEADER_SIZE: usize = 0x40;
+const SA_ENTRY_OFFSET: usize = 0x08;
+
+// primary opcodes the R4300 doesn't implement (COP2/COP3 loads and stores, and the gaps)
+const RESERVED_OPCODES: &[u32] = &[
+    0x13, 0x1C, 0x1D, 0x1E, 0x1F, 0x32, 0x33, 0x36, 0x3A, 0x3B, 0x3E,
+];
+
+// SPECIAL function codes the R4300 doesn't implement
+const RESERVED_FUNCTS: &[u32] = &[
+    0x01, 0x05, 0x0A, 0x0B, 0x0E, 0x15, 0x28, 0x29, 0x35, 0x37, 0x39, 0x3D,
+];
+
+/// Rough check for whether a big-endian word could be an R4300 instruction
+pub fn is_instruction(word: u32) -> bool {
+    match word >> 26 {
+        0x00 => !RESERVED_FUNCTS.contains(&(word & 0x3F)),
+        opcode => !RESERVED_OPCODES.contains(&opcode),
+    }
+}
+
+/// Extents of a flat SK image, as offsets from its start (which is where the bootrom jumps to)
+#[derive(Debug, Clone, Copy)]
+pub struct SkImage {
+    /// End of the run of instructions from the entry point; anything after it is treated as data
+    pub text_end: usize,
+    /// End of the last nonzero byte
+    pub data_end: usize,
+}
+
+impl SkImage {
+    pub fn parse(sk: &[u8]) -> Self {
+        let data_end = sk.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1);
+
+        let text_words = sk
+            .chunks_exact(INSTRUCTION_SIZE)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .take_while(|&w| is_instruction(w))
+            .count();
+
+        // zeroes are valid instructions (nop), but trailing ones are just padding
+        let text_end =
+            (text_words * INSTRUCTION_SIZE).min(data_end.next_multiple_of(INSTRUCTION_SIZE));
+
+        Self { text_end, data_end }
+    }
+
+    fn address(&self, offset: usize) -> u32 {
+        SK_LOAD_ADDRESS + offset as u32
+    }
+}
+
+/// Checks a flat SK image against the `SK_SIZE` bytes the bootrom loads,
+/// returning it with any zero padding past that trimmed off
+pub fn check_sk(sk: &[u8]) -> Result<Vec<u8>, MakeSKSAError> {
+    let image = SkImage::parse(sk);
+
+    if image.data_end > SK_SIZE {
+        return Err(MakeSKSAError::ComponentTooLong(
+            SKSAComponent::Sk,
+            image.data_end,
+            SK_SIZE,
+        ));
+    }
+
+    if sk.len() > SK_SIZE {
+        eprintln!(
+            "Warning: ignoring 0x{:X} bytes of zero padding past the 0x{SK_SIZE:X} bytes the bootrom loads",
+            sk.len() - SK_SIZE
+        );
+    }
+
+    if image.text_end == 0 {
+        eprintln!(
+            "Warning: SK does not start with a MIPS instruction at its entry point (0x{SK_LOAD_ADDRESS:08X})"
+        );
+    }
+
+    eprintln!(
+        "SK: entry 0x{SK_LOAD_ADDRESS:08X}, text 0x{:08X}-0x{:08X}, data 0x{:08X}-0x{:08X}, 0x{:X} bytes of padding",
+        image.address(0),
+        image.address(image.text_end),
+        image.address(image.text_end),
+        image.address(image.data_end.max(image.text_end)),
+        SK_SIZE - image.data_end.max(image.text_end)
+    );
+
+    Ok(sk[..sk.len().min(SK_SIZE)].to_vec())
+}
+
+/// Reads the entry point from an SA's header; the boot code copies the SA there before
+/// jumping to it, so it's also the load address
+pub fn sa_entry(data: &[u8], component: SKSAComponent) -> Result<u32, MakeSKSAError> {
+    if data.len() < SA_HEADER_SIZE {
+        return Err(MakeSKSAError::BadSaHeader(
+            component,
+            format!("only 0x{:X} bytes, too short for a header", data.len()),
+        ));
+    }
+
+    Ok(u32::from_be_bytes(
+        data[SA_ENTRY_OFFSET..][..4].try_into().unwrap(),
+    ))
+}
+
+pub fn check_sa_entry(
+    entry: u32,
+    component: SKSAComponent,
+    expected: Option<u32>,
+) -> Result<(), MakeSKSAError> {
+    if !SA_RANGE.contains(&entry) {
+        return Err(MakeSKSAError::EntryOutOfRange(
+            component,
+            entry,
+            SA_RANGE.start,
+            SA_RANGE.end,
+        ));
+    }
+
+    if let Some(expected) = expected {
+        if entry != expected {
+            return Err(MakeSKSAError::WrongLoadAddress(component, entry, expected));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,443 @@
+use bb::BLOCK_SIZE;
+
+use crate::sksa::SKSA;
+use crate::MakeSKSAError;
+
+pub const PAGE_SIZE: usize = 0x200;
+pub const SPARE_SIZE: usize = 0x10;
+pub const PAGES_PER_BLOCK: usize = BLOCK_SIZE / PAGE_SIZE;
+
+const ECC_CHUNK_SIZE: usize = 0x100;
+
+// spare offsets of the ECC for the first and second halves of each page
+const ECC_OFFSETS: [usize; 2] = [0x0D, 0x08];
+
+// anything other than 0xFF here in a block's first page marks the block as bad
+const BAD_BLOCK_OFFSET: usize = 0x05;
+
+pub struct NandImage {
+    pub data: Vec<u8>,
+    pub spare: Option<Vec<u8>>,
+}
+
+/// Where one copy of the SKSA ended up
+#[derive(Debug)]
+pub struct CopyPlacement {
+    pub start: usize,
+    /// The physical block each block of the SKSA was written to
+    pub map: Vec<usize>,
+    /// Stale blocks erased after the copy
+    pub erased: Vec<usize>,
+}
+
+fn ecc_table() -> [u8; 256] {
+    let mut table = [0; 256];
+
+    let parity = |b: u8, mask: u8| ((b & mask).count_ones() & 1) as u8;
+
+    for (b, entry) in table.iter_mut().enumerate() {
+        let b = b as u8;
+
+        *entry = parity(b, 0x55)
+            | (parity(b, 0xAA) << 1)
+            | (parity(b, 0x33) << 2)
+            | (parity(b, 0xCC) << 3)
+            | (parity(b, 0x0F) << 4)
+            | (parity(b, 0xF0) << 5)
+            | (parity(b, 0xFF) << 6);
+    }
+
+    table
+}
+
+/// SmartMedia-style Hamming code over 256 bytes, correcting single-bit errors
+pub fn ecc(data: &[u8]) -> [u8; 3] {
+    let table = ecc_table();
+
+    let mut column = 0u8;
+    let mut line_odd = 0u8;
+    let mut line_even = 0u8;
+
+    for (index, &b) in data.iter().take(ECC_CHUNK_SIZE).enumerate() {
+        let entry = table[b as usize];
+
+        column ^= entry & 0x3F;
+
+        if entry & 0x40 != 0 {
+            line_odd ^= index as u8;
+            line_even ^= !(index as u8);
+        }
+    }
+
+    let interleave = |bits: std::ops::Range<u32>| {
+        bits.rev().fold(0u8, |acc, bit| {
+            (acc << 2) | (((line_odd >> bit) & 1) << 1) | ((line_even >> bit) & 1)
+        })
+    };
+
+    [
+        !interleave(4..8),
+        !interleave(0..4),
+        ((!column) << 2) | 0x03,
+    ]
+}
+
+impl NandImage {
+    pub fn new(data: Vec<u8>, spare: Option<Vec<u8>>) -> Result<Self, MakeSKSAError> {
+        if !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(MakeSKSAError::BadNandSize(data.len(), BLOCK_SIZE));
+        }
+
+        if let Some(spare) = &spare {
+            let expected = data.len() / PAGE_SIZE * SPARE_SIZE;
+            if spare.len() != expected {
+                return Err(MakeSKSAError::BadSpareSize(spare.len(), expected));
+            }
+        }
+
+        Ok(Self { data, spare })
+    }
+
+    /// Splits an image with the spare data stored after every page
+    pub fn from_interleaved(buf: &[u8]) -> Result<Self, MakeSKSAError> {
+        let page = PAGE_SIZE + SPARE_SIZE;
+
+        if !buf.len().is_multiple_of(page * PAGES_PER_BLOCK) {
+            return Err(MakeSKSAError::BadNandSize(
+                buf.len(),
+                page * PAGES_PER_BLOCK,
+            ));
+        }
+
+        let mut data = vec![];
+        let mut spare = vec![];
+
+        for chunk in buf.chunks_exact(page) {
+            let (d, s) = chunk.split_at(PAGE_SIZE);
+            data.extend(d);
+            spare.extend(s);
+        }
+
+        Self::new(data, Some(spare))
+    }
+
+    pub fn to_interleaved(&self) -> Vec<u8> {
+        let spare = self.spare.as_deref().unwrap_or(&[]);
+
+        self.data
+            .chunks_exact(PAGE_SIZE)
+            .zip(spare.chunks_exact(SPARE_SIZE))
+            .flat_map(|(d, s)| d.iter().chain(s))
+            .copied()
+            .collect()
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.data.len() / BLOCK_SIZE
+    }
+
+    fn update_ecc(&mut self, page: usize) {
+        let Some(spare) = &mut self.spare else {
+            return;
+        };
+
+        let data = &self.data[page * PAGE_SIZE..][..PAGE_SIZE];
+        let spare = &mut spare[page * SPARE_SIZE..][..SPARE_SIZE];
+
+        for (chunk, offset) in data.chunks_exact(ECC_CHUNK_SIZE).zip(ECC_OFFSETS) {
+            spare[offset..][..3].copy_from_slice(&ecc(chunk));
+        }
+    }
+
+    /// Overwrites whole blocks starting at `block`, regenerating ECC for every page written
+    pub fn write_blocks(&mut self, block: usize, data: &[u8]) -> Result<(), MakeSKSAError> {
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+
+        if block + blocks > self.blocks() {
+            return Err(MakeSKSAError::NandTooSmall(block + blocks, self.blocks()));
+        }
+
+        let start = block * BLOCK_SIZE;
+        let end = start + blocks * BLOCK_SIZE;
+
+        self.data[start..start + data.len()].copy_from_slice(data);
+        self.data[start + data.len()..end].fill(0);
+
+        for page in start / PAGE_SIZE..end / PAGE_SIZE {
+            self.update_ecc(page);
+        }
+
+        Ok(())
+    }
+
+    /// Without spare data, every block is assumed to be good
+    pub fn is_bad_block(&self, block: usize) -> bool {
+        self.spare.as_ref().is_some_and(|spare| {
+            spare[block * PAGES_PER_BLOCK * SPARE_SIZE + BAD_BLOCK_OFFSET] != 0xFF
+        })
+    }
+
+    /// Writes `data` block by block into the good blocks from `block` onwards,
+    /// returning the physical block each block of `data` ended up in
+    pub fn write_skipping_bad(
+        &mut self,
+        block: usize,
+        data: &[u8],
+    ) -> Result<Vec<usize>, MakeSKSAError> {
+        let needed = data.len().div_ceil(BLOCK_SIZE);
+
+        let good = (block..self.blocks())
+            .filter(|&b| !self.is_bad_block(b))
+            .collect::<Vec<_>>();
+
+        if good.len() < needed {
+            return Err(MakeSKSAError::NandTooSmall(needed, good.len()));
+        }
+
+        let map = good[..needed].to_vec();
+
+        for (chunk, &physical) in data.chunks(BLOCK_SIZE).zip(&map) {
+            self.write_blocks(physical, chunk)?;
+        }
+
+        Ok(map)
+    }
+
+    /// Erases every good block from `block` up to (but not including) `end`,
+    /// returning the blocks erased
+    pub fn erase_skipping_bad(&mut self, block: usize, end: usize) -> Vec<usize> {
+        let erased = (block..end.min(self.blocks()))
+            .filter(|&b| !self.is_bad_block(b))
+            .collect::<Vec<_>>();
+
+        for &b in &erased {
+            self.data[b * BLOCK_SIZE..][..BLOCK_SIZE].fill(0xFF);
+
+            if let Some(spare) = &mut self.spare {
+                spare[b * PAGES_PER_BLOCK * SPARE_SIZE..][..PAGES_PER_BLOCK * SPARE_SIZE]
+                    .fill(0xFF);
+            }
+        }
+
+        erased
+    }
+
+    /// Reads every good block from `block` up to (but not including) `end`,
+    /// returning the data and the physical block each block of it came from
+    pub fn read_skipping_bad(&self, block: usize, end: usize) -> (Vec<u8>, Vec<usize>) {
+        let map = (block..end.min(self.blocks()))
+            .filter(|&b| !self.is_bad_block(b))
+            .collect::<Vec<_>>();
+
+        let data = map
+            .iter()
+            .flat_map(|&b| &self.data[b * BLOCK_SIZE..][..BLOCK_SIZE])
+            .copied()
+            .collect();
+
+        (data, map)
+    }
+
+    /// Writes a copy of `sksa` at each of the (sorted) `copies`, skipping bad blocks
+    pub fn write_copies(
+        &mut self,
+        sksa: &[u8],
+        copies: &[usize],
+    ) -> Result<Vec<CopyPlacement>, MakeSKSAError> {
+        let mut placements = vec![];
+
+        for (index, &start) in copies.iter().enumerate() {
+            let next = copies.get(index + 1).copied();
+
+            // whatever was here before can run on past the new copy, and anything left of it would
+            // be read back as more SAs, so everything up to the next copy (or, after the last one,
+            // the end of the old SKSA) gets erased
+            let stale_end = next.unwrap_or_else(|| {
+                let (old, old_map) = self.read_skipping_bad(start, self.blocks());
+                SKSA::parse_prefix(&old).map_or(start, |old| {
+                    old_map[old.size().div_ceil(BLOCK_SIZE) - 1] + 1
+                })
+            });
+
+            let map = self.write_skipping_bad(start, sksa)?;
+            let last = *map.last().unwrap();
+
+            if let Some(next) = next {
+                if last >= next {
+                    return Err(MakeSKSAError::CopiesOverlap(start, next));
+                }
+            }
+
+            let erased = self.erase_skipping_bad(last + 1, stale_end);
+
+            placements.push(CopyPlacement { start, map, erased });
+        }
+
+        Ok(placements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ecc_known_answers() {
+        assert_eq!(ecc(&[0xFF; ECC_CHUNK_SIZE]), [0xFF, 0xFF, 0xFF]);
+
+        let mut data = [0; ECC_CHUNK_SIZE];
+        data[0xFF] = 0x01;
+        assert_eq!(ecc(&data), [0x55, 0x55, 0xAB]);
+
+        let mut data = [0; ECC_CHUNK_SIZE];
+        data[0x5A] = 0x08;
+        assert_eq!(ecc(&data), [0x99, 0x66, 0x97]);
+    }
+
+    #[test]
+    fn ecc_locates_single_bit_errors() {
+        let data = (0..ECC_CHUNK_SIZE)
+            .map(|i| (i * 37 + 11) as u8)
+            .collect::<Vec<_>>();
+        let good = ecc(&data);
+
+        for (index, bit) in [(0x00, 0), (0x01, 7), (0x5A, 3), (0xFF, 6)] {
+            let mut bad = data.clone();
+            bad[index] ^= 1 << bit;
+
+            let syndrome = ecc(&bad)
+                .iter()
+                .zip(good)
+                .map(|(a, b)| a ^ b)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                syndrome.iter().map(|b| b.count_ones()).sum::<u32>(),
+                11,
+                "a single-bit error should flip exactly one bit of every parity pair"
+            );
+
+            // the upper bit of each pair is set where the error's address bit is
+            let upper = |bits: u16, pairs: u16| {
+                (0..pairs).fold(0, |acc, pair| {
+                    acc | (((bits >> (pair * 2 + 1)) & 1) << pair)
+                })
+            };
+
+            let line = u16::from_be_bytes([syndrome[0], syndrome[1]]);
+            assert_eq!(upper(line, 8) as usize, index);
+            assert_eq!(upper((syndrome[2] >> 2) as u16, 3), bit);
+        }
+    }
+
+    const BLOCKS: usize = 16;
+
+    /// A NAND image full of `fill`, with spare data marking `bad` as bad blocks
+    fn make_nand(fill: u8, bad: &[usize]) -> NandImage {
+        let mut spare = vec![0xFF; BLOCKS * PAGES_PER_BLOCK * SPARE_SIZE];
+        for &block in bad {
+            spare[block * PAGES_PER_BLOCK * SPARE_SIZE + BAD_BLOCK_OFFSET] = 0x00;
+        }
+
+        NandImage::new(vec![fill; BLOCKS * BLOCK_SIZE], Some(spare)).unwrap()
+    }
+
+    fn block(nand: &NandImage, block: usize) -> &[u8] {
+        &nand.data[block * BLOCK_SIZE..][..BLOCK_SIZE]
+    }
+
+    /// Three blocks, each filled with its own index plus one
+    fn three_blocks() -> Vec<u8> {
+        (0..3u8)
+            .flat_map(|b| [b + 1; BLOCK_SIZE])
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn write_skips_bad_blocks() {
+        let mut nand = make_nand(0xAA, &[2, 3]);
+
+        let map = nand.write_skipping_bad(1, &three_blocks()).unwrap();
+        assert_eq!(map, [1, 4, 5]);
+
+        for (index, &physical) in map.iter().enumerate() {
+            assert!(block(&nand, physical).iter().all(|&b| b == index as u8 + 1));
+        }
+        for untouched in [0, 2, 3, 6] {
+            assert!(block(&nand, untouched).iter().all(|&b| b == 0xAA));
+        }
+
+        // the bad block markers are left alone
+        assert!(nand.is_bad_block(2) && nand.is_bad_block(3));
+        assert!(!nand.is_bad_block(4));
+    }
+
+    #[test]
+    fn write_runs_out_of_good_blocks() {
+        let mut nand = make_nand(0xAA, &[14]);
+
+        assert!(matches!(
+            nand.write_skipping_bad(13, &three_blocks()),
+            Err(MakeSKSAError::NandTooSmall(3, 2))
+        ));
+    }
+
+    #[test]
+    fn erase_and_read_skip_bad_blocks() {
+        let mut nand = make_nand(0xAA, &[5]);
+
+        assert_eq!(nand.erase_skipping_bad(4, 7), [4, 6]);
+        assert!(block(&nand, 4).iter().all(|&b| b == 0xFF));
+        assert!(block(&nand, 5).iter().all(|&b| b == 0xAA));
+        assert!(block(&nand, 6).iter().all(|&b| b == 0xFF));
+        assert!(nand.is_bad_block(5));
+
+        // an end past the image is clamped
+        assert_eq!(nand.erase_skipping_bad(14, BLOCKS + 4), [14, 15]);
+
+        let (data, map) = nand.read_skipping_bad(3, 8);
+        assert_eq!(map, [3, 4, 6, 7]);
+        assert_eq!(data.len(), 4 * BLOCK_SIZE);
+        assert!(data[..BLOCK_SIZE].iter().all(|&b| b == 0xAA));
+        assert!(data[BLOCK_SIZE..3 * BLOCK_SIZE].iter().all(|&b| b == 0xFF));
+        assert!(data[3 * BLOCK_SIZE..].iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn copies_erase_up_to_the_next_copy() {
+        let mut nand = make_nand(0xAA, &[1, 6]);
+
+        let copies = nand.write_copies(&three_blocks(), &[0, 8]).unwrap();
+
+        assert_eq!(copies[0].map, [0, 2, 3]);
+        assert_eq!(copies[0].erased, [4, 5, 7]);
+        assert_eq!(copies[1].map, [8, 9, 10]);
+        // nothing that parses as an SKSA was there before, so there's nothing stale after it
+        assert!(copies[1].erased.is_empty());
+
+        for erased in [4, 5, 7] {
+            assert!(block(&nand, erased).iter().all(|&b| b == 0xFF));
+        }
+        for untouched in [1, 6, 11, 15] {
+            assert!(block(&nand, untouched).iter().all(|&b| b == 0xAA));
+        }
+    }
+
+    #[test]
+    fn copies_overlap() {
+        let mut nand = make_nand(0xAA, &[]);
+        assert!(matches!(
+            nand.write_copies(&three_blocks(), &[0, 2]),
+            Err(MakeSKSAError::CopiesOverlap(0, 2))
+        ));
+
+        // fits on its own, but not once a bad block pushes it along
+        let mut nand = make_nand(0xAA, &[1]);
+        assert!(matches!(
+            nand.write_copies(&three_blocks(), &[0, 3]),
+            Err(MakeSKSAError::CopiesOverlap(0, 3))
+        ));
+
+        let mut nand = make_nand(0xAA, &[]);
+        assert!(nand.write_copies(&three_blocks(), &[0, 3]).is_ok());
+    }
+}
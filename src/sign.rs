@@ -0,0 +1,42 @@
+use bb::CmdHead;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+use sha1::{Digest, Sha1};
+
+use crate::{MakeSKSAError, SKSAComponent};
+
+const SIGNATURE_SIZE: usize = 256;
+
+pub fn read_key(pem: &str) -> Result<RsaPrivateKey, MakeSKSAError> {
+    RsaPrivateKey::from_pkcs8_pem(pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+        .map_err(MakeSKSAError::BadSigningKeyPem)
+}
+
+// the signature covers the whole CmdHead up to (but not including) the signature itself
+pub fn sign_cmd(
+    cmd: &mut CmdHead,
+    key: &RsaPrivateKey,
+    component: SKSAComponent,
+) -> Result<(), MakeSKSAError> {
+    // only a 2048-bit key gives a signature that fits
+    if key.size() != SIGNATURE_SIZE {
+        return Err(MakeSKSAError::BadSigningKey(key.size() * 8));
+    }
+
+    let buf = cmd
+        .to_buf()
+        .map_err(|e| MakeSKSAError::BadCmdHead(component, e.into()))?;
+    let hash = Sha1::digest(&buf[..buf.len() - SIGNATURE_SIZE]);
+
+    let signature = key
+        .sign(Pkcs1v15Sign::new::<Sha1>(), &hash)
+        .map_err(|e| MakeSKSAError::Signing(component, e))?;
+    cmd.signature = signature
+        .try_into()
+        .expect("a 2048-bit key gives a 256-byte signature");
+
+    Ok(())
+}
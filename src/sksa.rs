@@ -0,0 +1,252 @@
+use bb::{BbAesKey, CmdHead, BLOCK_SIZE};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use soft_aes::aes::aes_dec_cbc;
+
+use std::fmt::Display;
+
+use crate::{MakeSKSAError, SKSAComponent, DUMMY_CERTS_CRLS, SK_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SKSARegion {
+    Sk,
+    Header(SKSAComponent),
+    Body(SKSAComponent),
+}
+
+impl Display for SKSARegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sk => write!(f, "SK"),
+            Self::Header(c) => write!(f, "{c} header"),
+            Self::Body(c) => write!(f, "{c} body"),
+        }
+    }
+}
+
+pub struct SystemApp<'a> {
+    pub component: SKSAComponent,
+    pub cmd: CmdHead,
+    pub cmd_block: &'a [u8],
+    pub body: &'a [u8],
+}
+
+impl<'a> SystemApp<'a> {
+    fn parse(buf: &'a [u8], component: SKSAComponent) -> Result<(Self, &'a [u8]), MakeSKSAError> {
+        if buf.len() < BLOCK_SIZE {
+            return Err(MakeSKSAError::Truncated(component, BLOCK_SIZE, buf.len()));
+        }
+
+        let (cmd_block, rest) = buf.split_at(BLOCK_SIZE);
+        let cmd = CmdHead::read_from_buf(cmd_block)
+            .map_err(|e| MakeSKSAError::BadCmdHead(component, e.into()))?;
+
+        let size = cmd.size as usize;
+        if rest.len() < size {
+            return Err(MakeSKSAError::Truncated(component, size, rest.len()));
+        }
+
+        let (body, rest) = rest.split_at(size);
+
+        Ok((
+            Self {
+                component,
+                cmd,
+                cmd_block,
+                body,
+            },
+            rest,
+        ))
+    }
+
+    pub fn head_len(&self) -> Result<usize, MakeSKSAError> {
+        self.cmd
+            .to_buf()
+            .map(|buf| buf.len())
+            .map_err(|e| MakeSKSAError::BadCmdHead(self.component, e.into()))
+    }
+
+    pub fn certs_crls(&self) -> Result<&'a [u8], MakeSKSAError> {
+        Ok(&self.cmd_block[self.head_len()?..])
+    }
+
+    pub fn title_key(&self, common_key: &BbAesKey) -> Result<BbAesKey, MakeSKSAError> {
+        let key = aes_dec_cbc(&self.cmd.key, common_key, &self.cmd.common_cmd_iv, None)
+            .map_err(|e| MakeSKSAError::Decryption(self.component, e.to_string().into()))?;
+
+        // a single block in, a single block out
+        Ok(key.try_into().unwrap())
+    }
+
+    pub fn decrypt(&self, common_key: &BbAesKey) -> Result<Vec<u8>, MakeSKSAError> {
+        aes_dec_cbc(self.body, &self.title_key(common_key)?, &self.cmd.iv, None)
+            .map_err(|e| MakeSKSAError::Decryption(self.component, e.to_string().into()))
+    }
+}
+
+// erased NAND reads back as 0xFF, and `write_blocks` pads with 0x00
+fn is_blank(buf: &[u8]) -> bool {
+    buf.len() < BLOCK_SIZE || {
+        let block = &buf[..BLOCK_SIZE];
+        block.iter().all(|&b| b == 0xFF) || block.iter().all(|&b| b == 0x00)
+    }
+}
+
+pub struct SKSA<'a> {
+    pub sk: &'a [u8],
+    pub sas: Vec<SystemApp<'a>>,
+}
+
+impl<'a> SKSA<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, MakeSKSAError> {
+        Self::parse_impl(buf, false)
+    }
+
+    /// Like `parse`, but stops at the first blank block after an SA instead of
+    /// requiring the SKSA to fill `buf` (for NAND images and dumps padded out to a whole area)
+    pub fn parse_prefix(buf: &'a [u8]) -> Result<Self, MakeSKSAError> {
+        Self::parse_impl(buf, true)
+    }
+
+    fn parse_impl(buf: &'a [u8], prefix: bool) -> Result<Self, MakeSKSAError> {
+        if buf.len() < SK_SIZE {
+            return Err(MakeSKSAError::Truncated(
+                SKSAComponent::Sk,
+                SK_SIZE,
+                buf.len(),
+            ));
+        }
+
+        let (sk, mut rest) = buf.split_at(SK_SIZE);
+
+        let mut sas = vec![];
+
+        // there's always at least one SA; anything after it is another SA
+        loop {
+            let (sa, next) = SystemApp::parse(rest, SKSAComponent::Sa(sas.len() + 1))?;
+            sas.push(sa);
+            rest = next;
+
+            if rest.is_empty() || (prefix && is_blank(rest)) {
+                break;
+            }
+        }
+
+        Ok(Self { sk, sas })
+    }
+
+    pub fn size(&self) -> usize {
+        self.sk.len()
+            + self
+                .sas
+                .iter()
+                .map(|sa| sa.cmd_block.len() + sa.body.len())
+                .sum::<usize>()
+    }
+
+    pub fn regions(&self) -> Vec<(SKSARegion, &'a [u8])> {
+        let mut rv = vec![(SKSARegion::Sk, self.sk)];
+
+        for sa in &self.sas {
+            rv.push((SKSARegion::Header(sa.component), sa.cmd_block));
+            rv.push((SKSARegion::Body(sa.component), sa.body));
+        }
+
+        rv
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SKLayout {
+    pub offset: usize,
+    pub block: usize,
+    pub size: usize,
+    pub sha1: String,
+    /// Only known when the SK could be decrypted
+    pub padded: Option<bool>,
+    pub code_size: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SALayout {
+    pub name: String,
+    pub header_offset: usize,
+    pub header_block: usize,
+    pub body_offset: usize,
+    pub body_block: usize,
+    pub body_size: usize,
+    pub content_id: u32,
+    pub key: String,
+    pub iv: String,
+    pub common_cmd_iv: String,
+    pub hash: String,
+    pub signature: String,
+    pub certs_crls_size: usize,
+    pub certs_crls_sha1: String,
+    pub dummy_certs_crls: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SKSALayout {
+    pub size: usize,
+    pub block_size: usize,
+    pub sk: SKLayout,
+    pub sas: Vec<SALayout>,
+}
+
+impl SALayout {
+    fn new(sa: &SystemApp, header_offset: usize) -> Result<Self, MakeSKSAError> {
+        let certs_crls = sa.certs_crls()?;
+        let body_offset = header_offset + sa.cmd_block.len();
+
+        Ok(Self {
+            name: sa.component.to_string(),
+            header_offset,
+            header_block: header_offset / BLOCK_SIZE,
+            body_offset,
+            body_block: body_offset / BLOCK_SIZE,
+            body_size: sa.body.len(),
+            content_id: sa.cmd.content_id,
+            key: hex::encode(sa.cmd.key),
+            iv: hex::encode(sa.cmd.iv),
+            common_cmd_iv: hex::encode(sa.cmd.common_cmd_iv),
+            hash: hex::encode(sa.cmd.hash),
+            signature: hex::encode(sa.cmd.signature),
+            certs_crls_size: certs_crls.len(),
+            certs_crls_sha1: hex::encode(Sha1::digest(certs_crls)),
+            dummy_certs_crls: certs_crls.starts_with(DUMMY_CERTS_CRLS),
+        })
+    }
+}
+
+impl<'a> SKSA<'a> {
+    /// `sk` is the decrypted SK, if the keys needed to decrypt it are available
+    pub fn layout(&self, sk: Option<&[u8]>) -> Result<SKSALayout, MakeSKSAError> {
+        let code_size = sk.map(|sk| sk.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1));
+
+        let sk_layout = SKLayout {
+            offset: 0,
+            block: 0,
+            size: self.sk.len(),
+            sha1: hex::encode(Sha1::digest(self.sk)),
+            padded: code_size.map(|c| c < self.sk.len()),
+            code_size,
+        };
+
+        let mut offset = self.sk.len();
+        let mut sas = vec![];
+
+        for sa in &self.sas {
+            let layout = SALayout::new(sa, offset)?;
+            offset = layout.body_offset + layout.body_size;
+            sas.push(layout);
+        }
+
+        Ok(SKSALayout {
+            size: offset,
+            block_size: BLOCK_SIZE,
+            sk: sk_layout,
+            sas,
+        })
+    }
+}